@@ -1,10 +1,68 @@
+from typing import Callable, Optional
+
 import numpy as np
 
-from .pycleora import SparseMatrix
+from .pycleora import CancelToken, SparseMatrix
+
+NORMALIZATIONS = ("l2", "l1", "none", "zscore")
+
+
+def normalize(embeddings, normalization: str = "l2"):
+    """Normalize embeddings after a propagation step.
+
+    l2 and l1 scale every vector to unit norm, zscore standardizes every dimension across entities,
+    none keeps the raw accumulations (e.g. for dot-product retrieval).
+    """
+    if normalization == "l2":
+        return embeddings / np.linalg.norm(embeddings, ord=2, axis=-1, keepdims=True)
+    if normalization == "l1":
+        return embeddings / np.linalg.norm(embeddings, ord=1, axis=-1, keepdims=True)
+    if normalization == "zscore":
+        return (embeddings - embeddings.mean(axis=0)) / embeddings.std(axis=0)
+    if normalization == "none":
+        return embeddings
+    raise ValueError(f"Unknown normalization {normalization!r}, expected one of {NORMALIZATIONS}")
+
+
+def embed_using_baseline_cleora(
+    graph,
+    feature_dim: int,
+    iter: int,
+    convergence_threshold: Optional[float] = None,
+    num_workers: Optional[int] = None,
+    normalization: str = "l2",
+    on_iteration: Optional[Callable[[int, np.ndarray], Optional[bool]]] = None,
+    max_neighbors: Optional[int] = None,
+    alpha: Optional[float] = None,
+):
+    """Propagate deterministically initialized embeddings over the graph `iter` times.
 
-def embed_using_baseline_cleora(graph, feature_dim: int, iter: int):
-    embeddings = graph.initialize_deterministically(feature_dim)
+    `on_iteration(i, embeddings)` is called after every iteration, e.g. to log norms or metrics.
+    Returning True from it stops the propagation early.
+    `max_neighbors` caps the neighbors hub nodes propagate from, sampled by edge weight.
+    `alpha` blends every propagated vector with the initial one, like the restart probability
+    of personalized PageRank: higher values keep embeddings more local.
+    """
+    if normalization not in NORMALIZATIONS:
+        raise ValueError(f"Unknown normalization {normalization!r}, expected one of {NORMALIZATIONS}")
+    if alpha is not None and not 0.0 <= alpha <= 1.0:
+        raise ValueError(f"alpha has to be between 0 and 1, got {alpha}")
+    embeddings = graph.initialize_deterministically(feature_dim, num_workers=num_workers)
+    # Blended in the same scale as the propagated vectors
+    initial = normalize(embeddings, normalization)
     for i in range(iter):
-        embeddings = graph.left_markov_propagate(embeddings)
-        embeddings /= np.linalg.norm(embeddings, ord=2, axis=-1, keepdims=True)
-    return embeddings
\ No newline at end of file
+        previous = embeddings
+        embeddings = graph.left_markov_propagate(embeddings, num_workers=num_workers, max_neighbors=max_neighbors)
+        if alpha is not None:
+            embeddings = (1.0 - alpha) * embeddings + alpha * initial
+        embeddings = normalize(embeddings, normalization)
+        if on_iteration is not None and on_iteration(i, embeddings) is True:
+            break
+        # Stop early once the mean cosine change between consecutive iterations is small enough
+        if convergence_threshold is not None:
+            cosine = np.sum(embeddings * previous, axis=-1) / (
+                np.linalg.norm(embeddings, ord=2, axis=-1) * np.linalg.norm(previous, ord=2, axis=-1)
+            )
+            if np.mean(1.0 - cosine) < convergence_threshold:
+                break
+    return embeddings
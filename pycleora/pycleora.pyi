@@ -1,8 +1,24 @@
-from typing import Any, Iterable, Optional, Self
+from typing import Any, Iterable, Literal, Optional, Self, TypeVar
 
 import numpy as np
 from numpy.typing import NDArray
 
+FloatT = TypeVar("FloatT", np.float32, np.float64)
+
+
+class CancelToken:
+    """Cancels graph building or propagation from any thread, the cancelled call raises RuntimeError."""
+
+    def __new__(cls) -> Self:
+        pass
+
+    def cancel(self) -> None:
+        pass
+
+    @property
+    def cancelled(self) -> bool:
+        pass
+
 
 class SparseMatrix:
     def __new__(cls, *args: Any) -> Self:
@@ -10,22 +26,75 @@ class SparseMatrix:
 
     @classmethod
     def from_iterator(
-        cls, hyperedges: Iterable[str], columns: str, hyperedge_trim_n: int = 16, num_workers: Optional[int] = None
+        cls,
+        hyperedges: Iterable[str],
+        columns: str,
+        hyperedge_trim_n: int = 16,
+        num_workers: Optional[int] = None,
+        # Keeps a 64-bit fingerprint of every distinct row until the graph is built, so memory grows
+        # with the number of distinct rows. Distinct rows with colliding fingerprints are dropped.
+        dedup_hyperedges: bool = False,
+        directed: bool = False,
+        min_entity_count: int = 1,
+        exclude_entities: Optional[list[str]] = None,
+        aliases: Optional[dict[str, str]] = None,
+        decay_halflife: Optional[float] = None,
+        decay_reference_time: Optional[float] = None,
+        max_hash_collisions: Optional[int] = None,
+        column_merges: Literal["warn", "fail", "separate"] = "warn",
+        on_bad_line: Literal["skip", "warn", "fail"] = "warn",
+        cancel_token: Optional[CancelToken] = None,
     ) -> Self:
         pass
 
     @classmethod
     def from_files(
-        cls, filepaths: list[str], columns: str, hyperedge_trim_n: int = 16, num_workers: Optional[int] = None
+        cls,
+        filepaths: list[str],
+        columns: str,
+        hyperedge_trim_n: int = 16,
+        num_workers: Optional[int] = None,
+        # Keeps a 64-bit fingerprint of every distinct row until the graph is built, so memory grows
+        # with the number of distinct rows. Distinct rows with colliding fingerprints are dropped.
+        dedup_hyperedges: bool = False,
+        directed: bool = False,
+        min_entity_count: int = 1,
+        exclude_entities: Optional[list[str]] = None,
+        aliases: Optional[dict[str, str]] = None,
+        decay_halflife: Optional[float] = None,
+        decay_reference_time: Optional[float] = None,
+        max_hash_collisions: Optional[int] = None,
+        column_merges: Literal["warn", "fail", "separate"] = "warn",
+        on_bad_line: Literal["skip", "warn", "fail"] = "warn",
+        cancel_token: Optional[CancelToken] = None,
     ) -> Self:
         pass
 
-    def left_markov_propagate(self, x: NDArray[np.float32], num_workers: Optional[int] = None) -> NDArray[np.float32]:
+    def left_markov_propagate(
+        self,
+        x: NDArray[FloatT],
+        num_workers: Optional[int] = None,
+        max_neighbors: Optional[int] = None,
+        cancel_token: Optional[CancelToken] = None,
+    ) -> NDArray[FloatT]:
         pass
 
     def symmetric_markov_propagate(
-        self, x: NDArray[np.float32], num_workers: Optional[int] = None
-    ) -> NDArray[np.float32]:
+        self,
+        x: NDArray[FloatT],
+        num_workers: Optional[int] = None,
+        max_neighbors: Optional[int] = None,
+        cancel_token: Optional[CancelToken] = None,
+    ) -> NDArray[FloatT]:
+        pass
+
+    def unnormalized_propagate(
+        self,
+        x: NDArray[FloatT],
+        num_workers: Optional[int] = None,
+        max_neighbors: Optional[int] = None,
+        cancel_token: Optional[CancelToken] = None,
+    ) -> NDArray[FloatT]:
         pass
 
     def get_entity_column_mask(self, column_name: str) -> NDArray[np.bool]:
@@ -34,5 +103,14 @@ class SparseMatrix:
     def entity_degrees(self) -> NDArray[np.float32]:
         pass
 
-    def initialize_deterministically(self, feature_dim: int, seed: int = 0) -> NDArray[np.float32]:
+    def entity_counts(self) -> NDArray[np.uint32]:
+        pass
+
+    def initialize_deterministically(
+        self,
+        feature_dim: int,
+        seed: int = 0,
+        num_workers: Optional[int] = None,
+        distribution: Literal["uniform", "gaussian"] = "uniform",
+    ) -> NDArray[np.float32]:
         pass
@@ -1,4 +1,15 @@
-use crate::sparse_matrix::SparseMatrixDescriptor;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasherDefault;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustc_hash::FxHasher;
+use thiserror::Error;
+
+use crate::entity::hash_entity;
+use crate::sparse_matrix::{create_sparse_matrix_descriptor, SparseMatrixDescriptor};
 
 #[derive(Debug)]
 pub struct Configuration {
@@ -7,18 +18,430 @@ pub struct Configuration {
     pub columns: Vec<Column>,
     pub hyperedge_trim_n: usize,
     pub num_workers_graph_building: usize,
+    /// Repeated identical rows contribute to the graph only once
+    pub dedup_hyperedges: bool,
+    /// Entities occurring fewer times are dropped from the graph
+    pub min_entity_count: u32,
+    /// Hashes of entities skipped during graph building, rows left without entities in a column
+    /// are skipped too
+    pub excluded_entities: HashSet<u64, BuildHasherDefault<FxHasher>>,
+    /// Canonical ids replacing their aliases before entities are hashed
+    pub aliases: HashMap<String, String, BuildHasherDefault<FxHasher>>,
+    /// Decay of row weights by the age of the row, requires a TIMESTAMP column
+    pub decay: Option<Decay>,
+    /// Graph building fails if more entities collide with hashes of other entities
+    pub max_hash_collisions: Option<usize>,
+    /// Handling of values occurring in more than one entity column
+    pub column_merges: ColumnMerges,
+    /// Handling of malformed input lines
+    pub on_bad_line: OnBadLine,
+    /// Graph building stops once the flag gets set, e.g. from another thread
+    pub cancel_flag: Option<Arc<AtomicBool>>,
+}
+
+impl Configuration {
+    /// Start building a validated configuration.
+    pub fn builder() -> ConfigurationBuilder {
+        ConfigurationBuilder::default()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(&self.cancel_flag, Some(flag) if flag.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConfigurationError {
+    #[error("Columns are not configured")]
+    MissingColumns,
+
+    #[error("Invalid columns: {0}")]
+    InvalidColumns(String),
+
+    #[error("{0}")]
+    InvalidRelation(&'static str),
+
+    #[error("At least two entity columns or a REFLEXIVE column are required, {0} entity columns configured")]
+    TooFewEntityColumns(usize),
+
+    #[error("hyperedge_trim_n must be greater than 0")]
+    InvalidHyperedgeTrimN,
+
+    #[error("num_workers must be greater than 0")]
+    InvalidNumWorkers,
+
+    #[error("decay_halflife must be a positive number")]
+    InvalidDecayHalflife,
+
+    #[error("decay_halflife and a TIMESTAMP column have to be configured together")]
+    IncompleteDecay,
+}
+
+/// Exponential decay of row weights, a row `halflife` older than `reference_time` counts half.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decay {
+    pub halflife: f64,
+    pub reference_time: f64,
+}
+
+impl Decay {
+    /// Weight multiplier for a row with the given timestamp, in the same unit as `halflife`.
+    pub fn weight(&self, timestamp: f64) -> f64 {
+        (-(self.reference_time - timestamp) / self.halflife).exp2()
+    }
+}
+
+/// What happens with a value occurring in more than one entity column, e.g. the same id used for
+/// a user and a product.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnMerges {
+    /// Values of different columns become one entity, which is reported in the log
+    Warn,
+
+    /// Graph building fails when values of different columns would become one entity
+    Fail,
+
+    /// Every column gets its own entities, all of them keep the raw value as their id
+    Separate,
+}
+
+/// What happens with a malformed input line, e.g. with a wrong number of columns, an empty field,
+/// an invalid weight or invalid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnBadLine {
+    /// The line is skipped, only the number of skipped lines is logged at the end
+    Skip,
+
+    /// The line is skipped and logged
+    Warn,
+
+    /// Graph building fails on the first malformed line
+    Fail,
+}
+
+/// Builds [`Configuration`], validating every setting before the pipeline starts.
+#[derive(Debug)]
+pub struct ConfigurationBuilder {
+    seed: Option<i64>,
+    columns: Option<String>,
+    hyperedge_trim_n: usize,
+    num_workers: Option<usize>,
+    dedup_hyperedges: bool,
+    directed: bool,
+    min_entity_count: u32,
+    excluded_entities: HashSet<u64, BuildHasherDefault<FxHasher>>,
+    aliases: HashMap<String, String, BuildHasherDefault<FxHasher>>,
+    decay_halflife: Option<f64>,
+    decay_reference_time: Option<f64>,
+    max_hash_collisions: Option<usize>,
+    column_merges: ColumnMerges,
+    on_bad_line: OnBadLine,
+    cancel_flag: Option<Arc<AtomicBool>>,
+}
+
+impl Default for ConfigurationBuilder {
+    fn default() -> Self {
+        ConfigurationBuilder {
+            seed: None,
+            columns: None,
+            hyperedge_trim_n: 16,
+            num_workers: None,
+            dedup_hyperedges: false,
+            directed: false,
+            min_entity_count: 1,
+            excluded_entities: Default::default(),
+            aliases: Default::default(),
+            decay_halflife: None,
+            decay_reference_time: None,
+            max_hash_collisions: None,
+            column_merges: ColumnMerges::Warn,
+            on_bad_line: OnBadLine::Warn,
+            cancel_flag: None,
+        }
+    }
+}
+
+impl ConfigurationBuilder {
+    pub fn seed(mut self, seed: Option<i64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Columns in the raw format, e.g. "complex::reflexive::product".
+    pub fn columns(mut self, columns: &str) -> Self {
+        self.columns = Some(columns.to_string());
+        self
+    }
+
+    /// In rows with more entities in a column, only the `hyperedge_trim_n` most frequent entities
+    /// of the input are connected to the others.
+    pub fn hyperedge_trim_n(mut self, hyperedge_trim_n: usize) -> Self {
+        self.hyperedge_trim_n = hyperedge_trim_n;
+        self
+    }
+
+    /// Number of graph building workers, all cores are used if not set.
+    pub fn num_workers(mut self, num_workers: Option<usize>) -> Self {
+        self.num_workers = num_workers;
+        self
+    }
+
+    /// Skip rows identical to an already processed one. A 64-bit fingerprint of every distinct row
+    /// is kept until the graph is built, distinct rows with colliding fingerprints are skipped too.
+    pub fn dedup_hyperedges(mut self, dedup_hyperedges: bool) -> Self {
+        self.dedup_hyperedges = dedup_hyperedges;
+        self
+    }
+
+    /// Create edges only from entities of the first column to entities of the second column,
+    /// instead of symmetric ones.
+    pub fn directed(mut self, directed: bool) -> Self {
+        self.directed = directed;
+        self
+    }
+
+    /// Drop entities which occur fewer than `min_entity_count` times in the input, together with
+    /// their edges. Degrees of the remaining entities account only for the kept edges.
+    pub fn min_entity_count(mut self, min_entity_count: u32) -> Self {
+        self.min_entity_count = min_entity_count;
+        self
+    }
+
+    /// Skip the given entity values, e.g. placeholders like "unknown" which would become hubs.
+    /// Values are compared after column transforms such as LOWERCASE and aliases.
+    pub fn exclude_entities<I, S>(mut self, entities: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.excluded_entities
+            .extend(entities.into_iter().map(|e| hash_entity(e.as_ref())));
+        self
+    }
+
+    /// Replace known duplicates, e.g. merged accounts, with their canonical id, given as
+    /// `(alias, canonical_id)` pairs. Aliases are resolved once, after column transforms.
+    pub fn aliases<I, K, V>(mut self, aliases: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.aliases.extend(
+            aliases
+                .into_iter()
+                .map(|(alias, canonical_id)| (alias.into(), canonical_id.into())),
+        );
+        self
+    }
+
+    /// Halve the weight of rows for every `decay_halflife` of their age, read from the TIMESTAMP
+    /// column. Timestamps and the halflife share a unit, e.g. seconds. Rows so old that their
+    /// weight underflows f32 create no edges.
+    pub fn decay_halflife(mut self, decay_halflife: Option<f64>) -> Self {
+        self.decay_halflife = decay_halflife;
+        self
+    }
+
+    /// Time the age of rows is measured from, the current unix time in seconds if not set.
+    pub fn decay_reference_time(mut self, decay_reference_time: Option<f64>) -> Self {
+        self.decay_reference_time = decay_reference_time;
+        self
+    }
+
+    /// Fail graph building when more distinct entities get merged into other entities because
+    /// of equal hashes. Collisions are always reported in the log.
+    pub fn max_hash_collisions(mut self, max_hash_collisions: Option<usize>) -> Self {
+        self.max_hash_collisions = max_hash_collisions;
+        self
+    }
+
+    /// How equal values of different entity columns are handled, they are merged with a warning
+    /// by default.
+    pub fn column_merges(mut self, column_merges: ColumnMerges) -> Self {
+        self.column_merges = column_merges;
+        self
+    }
+
+    /// How malformed input lines are handled, they are skipped with a warning by default.
+    pub fn on_bad_line(mut self, on_bad_line: OnBadLine) -> Self {
+        self.on_bad_line = on_bad_line;
+        self
+    }
+
+    /// Abort graph building with [`crate::error::CleoraError::Cancelled`] once `cancel_flag` is set.
+    /// Inputs are checked between rows, so already running jobs stop promptly.
+    pub fn cancel_flag(mut self, cancel_flag: Arc<AtomicBool>) -> Self {
+        self.cancel_flag = Some(cancel_flag);
+        self
+    }
+
+    pub fn build(self) -> Result<Configuration, ConfigurationError> {
+        let columns = self.columns.ok_or(ConfigurationError::MissingColumns)?;
+        let columns = parse_fields(&columns).map_err(ConfigurationError::InvalidColumns)?;
+        let entity_columns = columns.iter().filter(|c| c.is_entity()).count();
+        if entity_columns < 2 && !columns.iter().any(|c| c.is_entity() && c.reflexive) {
+            return Err(ConfigurationError::TooFewEntityColumns(entity_columns));
+        }
+        let mut matrix_desc = create_sparse_matrix_descriptor(&columns)
+            .map_err(ConfigurationError::InvalidRelation)?;
+        matrix_desc.directed = self.directed;
+        matrix_desc.separate_columns = self.column_merges == ColumnMerges::Separate;
+        if self.hyperedge_trim_n == 0 {
+            return Err(ConfigurationError::InvalidHyperedgeTrimN);
+        }
+        if self.num_workers == Some(0) {
+            return Err(ConfigurationError::InvalidNumWorkers);
+        }
+        let has_timestamp = columns.iter().any(|c| c.timestamp);
+        let decay = match self.decay_halflife {
+            Some(halflife) if !(halflife.is_finite() && halflife > 0.0) => {
+                return Err(ConfigurationError::InvalidDecayHalflife);
+            }
+            Some(halflife) if has_timestamp => Some(Decay {
+                halflife,
+                reference_time: self.decay_reference_time.unwrap_or_else(unix_time_now),
+            }),
+            None if !has_timestamp => None,
+            _ => return Err(ConfigurationError::IncompleteDecay),
+        };
+
+        Ok(Configuration {
+            seed: self.seed,
+            matrix_desc,
+            columns,
+            hyperedge_trim_n: self.hyperedge_trim_n,
+            num_workers_graph_building: self.num_workers.unwrap_or_else(num_cpus::get),
+            dedup_hyperedges: self.dedup_hyperedges,
+            min_entity_count: self.min_entity_count,
+            excluded_entities: self.excluded_entities,
+            aliases: self.aliases,
+            decay,
+            max_hash_collisions: self.max_hash_collisions,
+            column_merges: self.column_merges,
+            on_bad_line: self.on_bad_line,
+            cancel_flag: self.cancel_flag,
+        })
+    }
+}
+
+fn unix_time_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is after unix epoch")
+        .as_secs_f64()
 }
 
-#[derive(Debug, Default)]
+/// Format of an input file, recognized by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    /// Tab separated columns, entities of complex columns separated by space
+    Tsv,
+
+    /// JSON object per line, complex columns hold arrays of entities
+    JsonLines,
+
+    /// Comma separated columns with a header row naming them, fields may be quoted
+    Csv,
+}
+
+impl FileType {
+    /// Recognize the format of a file path, `.gz` and `.zst` compression suffixes are skipped.
+    pub fn from_path(path: &str) -> Option<FileType> {
+        let path = path
+            .strip_suffix(".gz")
+            .or_else(|| path.strip_suffix(".zst"))
+            .unwrap_or(path);
+        if path.ends_with(".tsv") {
+            Some(FileType::Tsv)
+        } else if path.ends_with(".jsonl") {
+            Some(FileType::JsonLines)
+        } else if path.ends_with(".csv") {
+            Some(FileType::Csv)
+        } else {
+            None
+        }
+    }
+}
+
+/// Separator of entities within a complex field, unless configured with `complex[<separator>]`
+pub const DEFAULT_COMPLEX_DELIMITER: char = ' ';
+
+#[derive(Debug)]
 pub struct Column {
     /// Name, header of the column
     pub name: String,
 
-    /// The field is composite, containing multiple entity identifiers separated by space
+    /// The field is composite, containing multiple entity identifiers separated by `delimiter`
     pub complex: bool,
 
+    /// Separator of entity identifiers within the field
+    pub delimiter: char,
+
     /// The field is reflexive, which means that it interacts with itself, additional output file is written for every such field
     pub reflexive: bool,
+
+    /// Entities of a reflexive field are not paired with themselves
+    pub no_self_loops: bool,
+
+    /// The field is not an entity but a numeric multiplier applied to the weight of every edge generated from the row
+    pub weight: bool,
+
+    /// The field is not an entity but the time of the row, used to decay its weight
+    pub timestamp: bool,
+
+    /// Entity identifiers are lowercased before hashing
+    pub lowercase: bool,
+
+    /// Surrounding whitespace is removed from entity identifiers before hashing
+    pub trim: bool,
+
+    /// The field is skipped, e.g. an extra column of the input which is not part of the graph
+    pub ignored: bool,
+}
+
+impl Default for Column {
+    fn default() -> Self {
+        Column {
+            name: String::new(),
+            complex: false,
+            delimiter: DEFAULT_COMPLEX_DELIMITER,
+            reflexive: false,
+            no_self_loops: false,
+            weight: false,
+            timestamp: false,
+            lowercase: false,
+            trim: false,
+            ignored: false,
+        }
+    }
+}
+
+impl Column {
+    /// Whether the field holds entities which become nodes of the graph
+    pub fn is_entity(&self) -> bool {
+        !self.weight && !self.timestamp && !self.ignored
+    }
+
+    /// Whether the value is empty once transformed, e.g. between doubled delimiters.
+    pub fn is_empty_value(&self, value: &str) -> bool {
+        if self.trim {
+            value.trim().is_empty()
+        } else {
+            value.is_empty()
+        }
+    }
+
+    /// Applies the configured transforms to an entity identifier, so different spellings of
+    /// an entity map to the same node.
+    pub fn transform<'a>(&self, entity: &'a str) -> Cow<'a, str> {
+        let entity = if self.trim { entity.trim() } else { entity };
+        if self.lowercase && entity.chars().any(char::is_uppercase) {
+            Cow::Owned(entity.to_lowercase())
+        } else {
+            Cow::Borrowed(entity)
+        }
+    }
 }
 
 /// Extract columns config based on raw strings.
@@ -31,7 +454,14 @@ pub fn parse_fields(columns: &str) -> Result<Vec<Column>, String> {
 
         let column_name: &str;
         let mut complex = false;
+        let mut delimiter = DEFAULT_COMPLEX_DELIMITER;
         let mut reflexive = false;
+        let mut no_self_loops = false;
+        let mut weight = false;
+        let mut timestamp = false;
+        let mut lowercase = false;
+        let mut trim = false;
+        let mut ignored = false;
 
         let parts_len = parts.len();
         if parts_len > 1 {
@@ -40,8 +470,30 @@ pub fn parse_fields(columns: &str) -> Result<Vec<Column>, String> {
             for &part in &parts[..column_name_idx] {
                 if part.eq_ignore_ascii_case("complex") {
                     complex = true;
+                } else if let Some(custom_delimiter) = parse_complex_delimiter(part) {
+                    complex = true;
+                    delimiter = custom_delimiter?;
                 } else if part.eq_ignore_ascii_case("reflexive") {
                     reflexive = true;
+                } else if part.eq_ignore_ascii_case("noselfloops") {
+                    no_self_loops = true;
+                } else if part.eq_ignore_ascii_case("weight") {
+                    weight = true;
+                } else if part.eq_ignore_ascii_case("timestamp") {
+                    timestamp = true;
+                } else if part.eq_ignore_ascii_case("lowercase") {
+                    lowercase = true;
+                } else if part.eq_ignore_ascii_case("trim") {
+                    trim = true;
+                } else if part.eq_ignore_ascii_case("ignore") {
+                    ignored = true;
+                } else if part.eq_ignore_ascii_case("transient") {
+                    let message = format!(
+                        "TRANSIENT modifier is not supported, all entities are embedded. \
+                         Use get_entity_column_mask to select entities of a column: {}",
+                        col
+                    );
+                    return Err(message);
                 } else {
                     let message = format!("Unrecognized column field modifier: {}", part);
                     return Err(message);
@@ -53,7 +505,14 @@ pub fn parse_fields(columns: &str) -> Result<Vec<Column>, String> {
         let column = Column {
             name: column_name.to_string(),
             complex,
+            delimiter,
             reflexive,
+            no_self_loops,
+            weight,
+            timestamp,
+            lowercase,
+            trim,
+            ignored,
         };
         columns.push(column);
     }
@@ -62,8 +521,35 @@ pub fn parse_fields(columns: &str) -> Result<Vec<Column>, String> {
     Ok(columns)
 }
 
+/// Parse `complex[<delimiter>]` modifier, e.g. `complex[,]`. Returns `None` for other modifiers.
+fn parse_complex_delimiter(part: &str) -> Option<Result<char, String>> {
+    let prefix = part.get(..8)?;
+    if !prefix.eq_ignore_ascii_case("complex[") || !part.ends_with(']') {
+        return None;
+    }
+    let mut delimiter = part[8..part.len() - 1].chars();
+    match (delimiter.next(), delimiter.next()) {
+        (Some(delimiter), None) => Some(Ok(delimiter)),
+        _ => Some(Err(format!(
+            "Complex field delimiter must be a single character: {}",
+            part
+        ))),
+    }
+}
+
 fn validate_column_modifiers(cols: Vec<Column>) -> Result<Vec<Column>, String> {
     for col in &cols {
+        let other_modifiers = col.complex
+            || col.reflexive
+            || col.no_self_loops
+            || col.weight
+            || col.timestamp
+            || col.lowercase
+            || col.trim;
+        if col.ignored && other_modifiers {
+            let message = format!("An IGNORE field cannot have other modifiers: {}", col.name);
+            return Err(message);
+        }
         // transient::reflexive - this would generate no output
         // transient::reflexive::complex - this would generate no output
         if col.reflexive && !col.complex {
@@ -73,6 +559,129 @@ fn validate_column_modifiers(cols: Vec<Column>) -> Result<Vec<Column>, String> {
             );
             return Err(message);
         }
+        if col.no_self_loops && !col.reflexive {
+            let message = format!("NOSELFLOOPS applies to REFLEXIVE fields only: {}", col.name);
+            return Err(message);
+        }
+        if col.weight && (col.complex || col.reflexive) {
+            let message = format!(
+                "A WEIGHT field cannot be COMPLEX or REFLEXIVE, it holds a single number: {}",
+                col.name
+            );
+            return Err(message);
+        }
+        if col.timestamp && (col.complex || col.reflexive || col.weight) {
+            let message = format!(
+                "A TIMESTAMP field cannot be COMPLEX, REFLEXIVE or WEIGHT, it holds a single number: {}",
+                col.name
+            );
+            return Err(message);
+        }
+        if !col.is_entity() && (col.lowercase || col.trim) {
+            let message = format!(
+                "LOWERCASE and TRIM apply to entities only, not to WEIGHT or TIMESTAMP fields: {}",
+                col.name
+            );
+            return Err(message);
+        }
+    }
+    if cols.iter().filter(|c| c.weight).count() > 1 {
+        return Err("At most one WEIGHT field is allowed".to_string());
+    }
+    if cols.iter().filter(|c| c.timestamp).count() > 1 {
+        return Err("At most one TIMESTAMP field is allowed".to_string());
     }
     Ok(cols)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{parse_fields, Configuration, ConfigurationError, Decay};
+
+    #[test]
+    fn parse_complex_delimiter() {
+        let columns = parse_fields("complex[,]::tags complex::reflexive::product").unwrap();
+        assert!(columns[0].complex);
+        assert_eq!(columns[0].delimiter, ',');
+        assert_eq!(columns[0].name, "tags");
+        assert_eq!(columns[1].delimiter, ' ');
+
+        assert!(parse_fields("complex[]::tags product").is_err());
+        assert!(parse_fields("complex[ab]::tags product").is_err());
+    }
+
+    #[test]
+    fn parse_ignored_column() {
+        let columns = parse_fields("ignore::id user product").unwrap();
+        assert!(columns[0].ignored);
+        assert!(!columns[0].is_entity());
+        assert!(columns[1].is_entity());
+
+        assert!(parse_fields("ignore::complex::id user product").is_err());
+        assert!(parse_fields("transient::user product").is_err());
+    }
+
+    #[test]
+    fn builder_validates_settings() {
+        let config = Configuration::builder()
+            .columns("complex::reflexive::product")
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        assert_eq!(config.num_workers_graph_building, 2);
+        assert_eq!(config.hyperedge_trim_n, 16);
+
+        let missing = Configuration::builder().build();
+        assert_eq!(missing.unwrap_err(), ConfigurationError::MissingColumns);
+
+        let single_column = Configuration::builder().columns("ignore::x a").build();
+        assert_eq!(
+            single_column.unwrap_err(),
+            ConfigurationError::TooFewEntityColumns(1)
+        );
+
+        let two_relations = Configuration::builder().columns("a b c").build();
+        assert!(matches!(
+            two_relations.unwrap_err(),
+            ConfigurationError::InvalidRelation(_)
+        ));
+
+        let no_workers = Configuration::builder()
+            .columns("a b")
+            .num_workers(Some(0))
+            .build();
+        assert_eq!(
+            no_workers.unwrap_err(),
+            ConfigurationError::InvalidNumWorkers
+        );
+    }
+
+    #[test]
+    fn decay_requires_timestamp_column() {
+        let config = Configuration::builder()
+            .columns("timestamp::t a b")
+            .decay_halflife(Some(10.0))
+            .decay_reference_time(Some(100.0))
+            .build()
+            .unwrap();
+        let decay = config.decay.unwrap();
+        assert_eq!(
+            decay,
+            Decay {
+                halflife: 10.0,
+                reference_time: 100.0
+            }
+        );
+        assert_eq!(decay.weight(100.0), 1.0);
+        assert_eq!(decay.weight(80.0), 0.25);
+
+        let no_timestamp = Configuration::builder()
+            .columns("a b")
+            .decay_halflife(Some(10.0))
+            .build();
+        assert_eq!(
+            no_timestamp.unwrap_err(),
+            ConfigurationError::IncompleteDecay
+        );
+    }
+}
@@ -1,24 +1,113 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::CleoraError;
 use crate::sparse_matrix::Edge;
 use crate::sparse_matrix::SparseMatrix;
-use ndarray::{Array, Array1, Array2, ArrayView2, Axis};
+use ndarray::{Array, Array1, Array2, ArrayView2, ArrayViewMut1, Axis, LinalgScalar};
 use rayon::prelude::*;
 use rayon::ThreadPoolBuilder;
 
 pub enum MarkovType {
     Left,
     Symmetric,
+    /// Raw accumulated edge weights, e.g. for dot-product retrieval on counts
+    Unnormalized,
 }
 
 pub struct NdArrayMatrix;
 
+/// Rows with more edges, e.g. of hubs, are propagated in parallel chunks of this many edges. The
+/// size is fixed, so results don't depend on the number of workers.
+const EDGE_CHUNK_SIZE: usize = 4096;
+
 impl NdArrayMatrix {
-    pub fn multiply(
+    /// Propagates `other` over the sparse matrix, in the precision of `other` (e.g. f32 or f64).
+    pub fn multiply<A>(
+        sparse_matrix_reader: &SparseMatrix,
+        other: ArrayView2<A>,
+        markov_type: MarkovType,
+        num_workers: usize,
+    ) -> Array2<A>
+    where
+        A: LinalgScalar + From<f32> + Send + Sync,
+    {
+        Self::propagate(
+            sparse_matrix_reader,
+            other,
+            markov_type,
+            num_workers,
+            None,
+            None,
+        )
+        .expect("Propagation without a cancel flag is not cancelled")
+    }
+
+    /// Same as `multiply`, but nodes with more than `max_neighbors` edges propagate from
+    /// `max_neighbors` neighbors sampled proportionally to the edge values. The sample of a node is
+    /// the same in every call, sums of the rows are preserved.
+    pub fn multiply_sampled<A>(
+        sparse_matrix_reader: &SparseMatrix,
+        other: ArrayView2<A>,
+        markov_type: MarkovType,
+        num_workers: usize,
+        max_neighbors: usize,
+    ) -> Array2<A>
+    where
+        A: LinalgScalar + From<f32> + Send + Sync,
+    {
+        Self::propagate(
+            sparse_matrix_reader,
+            other,
+            markov_type,
+            num_workers,
+            Some(max_neighbors),
+            None,
+        )
+        .expect("Propagation without a cancel flag is not cancelled")
+    }
+
+    /// Same as `multiply_sampled`, or `multiply` without `max_neighbors`, but stops with
+    /// [`CleoraError::Cancelled`] once `cancel_flag` is set, e.g. from another thread.
+    pub fn multiply_cancellable<A>(
         sparse_matrix_reader: &SparseMatrix,
-        other: ArrayView2<f32>,
+        other: ArrayView2<A>,
         markov_type: MarkovType,
         num_workers: usize,
-    ) -> Array2<f32> {
-        let mut new_matrix: Array2<f32> = Array::zeros(other.raw_dim());
+        max_neighbors: Option<usize>,
+        cancel_flag: &AtomicBool,
+    ) -> Result<Array2<A>, CleoraError>
+    where
+        A: LinalgScalar + From<f32> + Send + Sync,
+    {
+        Self::propagate(
+            sparse_matrix_reader,
+            other,
+            markov_type,
+            num_workers,
+            max_neighbors,
+            Some(cancel_flag),
+        )
+    }
+
+    fn propagate<A>(
+        sparse_matrix_reader: &SparseMatrix,
+        other: ArrayView2<A>,
+        markov_type: MarkovType,
+        num_workers: usize,
+        max_neighbors: Option<usize>,
+        cancel_flag: Option<&AtomicBool>,
+    ) -> Result<Array2<A>, CleoraError>
+    where
+        A: LinalgScalar + From<f32> + Send + Sync,
+    {
+        let is_cancelled = || matches!(cancel_flag, Some(flag) if flag.load(Ordering::Relaxed));
+        // Edges store only normalized values, raw weights are restored with the row sum
+        let edge_value = |edge: &Edge, row_sum: f32| match markov_type {
+            MarkovType::Left => edge.left_markov_value,
+            MarkovType::Symmetric => edge.symmetric_markov_value,
+            MarkovType::Unnormalized => edge.left_markov_value * row_sum,
+        };
+        let mut new_matrix: Array2<A> = Array::zeros(other.raw_dim());
         ThreadPoolBuilder::new()
             .num_threads(num_workers)
             .build()
@@ -28,34 +117,232 @@ impl NdArrayMatrix {
                     .axis_iter_mut(Axis(0))
                     .into_par_iter()
                     .zip(sparse_matrix_reader.slices.par_iter())
-                    .for_each(|(mut row, (start, end))| {
+                    .enumerate()
+                    .for_each(|(row_ix, (mut row, (start, end)))| {
+                        // Remaining rows are skipped, the result is dropped anyway
+                        if is_cancelled() {
+                            return;
+                        }
                         let edges = &sparse_matrix_reader.edges[*start..*end];
+                        if edges.is_empty() {
+                            // Nodes without outgoing edges keep their vector
+                            row.assign(&other.row(row_ix));
+                            return;
+                        }
+                        let row_sum = sparse_matrix_reader.entities[row_ix].row_sum;
+                        let edge_value = |edge: &Edge| edge_value(edge, row_sum);
+                        let add_edges = |target: &mut ArrayViewMut1<A>, edges: &[Edge]| {
+                            for edge in edges {
+                                let other_row = &other.row(edge.other_entity_ix as usize);
+                                target.scaled_add(A::from(edge_value(edge)), other_row);
+                            }
+                        };
 
-                        let new_row: Array1<f32> = edges
-                            .par_iter()
-                            .fold(
-                                || Array1::zeros(other.shape()[1]),
-                                |mut row, edge| {
-                                    let Edge {
-                                        left_markov_value,
-                                        symmetric_markov_value,
-                                        other_entity_ix,
-                                    } = edge;
-                                    let value = match markov_type {
-                                        MarkovType::Left => left_markov_value,
-                                        MarkovType::Symmetric => symmetric_markov_value,
-                                    };
-                                    let other_row = &other.row(*other_entity_ix as usize);
-                                    row.scaled_add(*value, other_row);
-                                    row
-                                },
-                            )
-                            .reduce_with(|v1, v2| v1 + v2)
-                            .expect("Must have at least one edge");
-
-                        row.assign(&new_row);
+                        match max_neighbors {
+                            Some(max_neighbors) if edges.len() > max_neighbors => {
+                                // Systematic sampling: samples are evenly spaced over the
+                                // cumulated edge values, each carries an equal share of the row
+                                let total: f32 = edges.iter().map(edge_value).sum();
+                                let step = total / max_neighbors as f32;
+                                let mut next_sample = step * sample_offset(row_ix);
+                                let mut remaining = max_neighbors;
+                                let mut cumulated = 0.0f32;
+                                for edge in edges {
+                                    cumulated += edge_value(edge);
+                                    let mut samples = 0;
+                                    while remaining > 0 && next_sample < cumulated {
+                                        samples += 1;
+                                        remaining -= 1;
+                                        next_sample += step;
+                                    }
+                                    if samples > 0 {
+                                        let other_row = &other.row(edge.other_entity_ix as usize);
+                                        row.scaled_add(A::from(step * samples as f32), other_row);
+                                    }
+                                }
+                            }
+                            _ if edges.len() > EDGE_CHUNK_SIZE => {
+                                // Partial sums of the chunks are added up in chunk order
+                                let partial_rows: Vec<Array1<A>> = edges
+                                    .par_chunks(EDGE_CHUNK_SIZE)
+                                    .map(|chunk| {
+                                        let mut partial_row = Array1::zeros(other.ncols());
+                                        add_edges(&mut partial_row.view_mut(), chunk);
+                                        partial_row
+                                    })
+                                    .collect();
+                                for partial_row in &partial_rows {
+                                    row.zip_mut_with(partial_row, |value, partial| {
+                                        *value = *value + *partial
+                                    });
+                                }
+                            }
+                            _ => {
+                                // Edges of a row are summed sequentially in a fixed order, so the
+                                // result does not depend on the number of workers
+                                add_edges(&mut row, edges);
+                            }
+                        }
                     });
             });
-        new_matrix
+        if is_cancelled() {
+            return Err(CleoraError::Cancelled);
+        }
+        Ok(new_matrix)
+    }
+}
+
+/// Position of the first sample of a row within the first step, in [0, 1), fixed for the row.
+fn sample_offset(row_ix: usize) -> f32 {
+    let hash = (row_ix as u64 ^ 0x5851_f42d_4c95_7f2d).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    (hash >> 40) as f32 / (1u64 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use ndarray::{arr2, s, Array2, Axis};
+
+    use crate::configuration::Configuration;
+    use crate::embedding::{MarkovType, NdArrayMatrix};
+    use crate::error::CleoraError;
+    use crate::pipeline::build_graph_from_iterator;
+
+    #[test]
+    fn sinks_keep_their_vectors() {
+        let config = Configuration::builder()
+            .columns("x y")
+            .num_workers(Some(2))
+            .directed(true)
+            .build()
+            .unwrap();
+        let lines = ["a\tb", "a\tc"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+        assert_eq!(graph.entity_ids, vec!["a", "b", "c"]);
+
+        let vectors: Array2<f32> = arr2(&[[1.0, 0.0], [0.0, 2.0], [4.0, 4.0]]);
+        let propagated = NdArrayMatrix::multiply(&graph, vectors.view(), MarkovType::Left, 2);
+        assert_eq!(propagated, arr2(&[[2.0, 3.0], [0.0, 2.0], [4.0, 4.0]]));
+    }
+
+    #[test]
+    fn cancelled_propagation() {
+        let config = Configuration::builder()
+            .columns("complex::reflexive::p")
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let lines = ["a b", "b c"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+        let vectors: Array2<f64> = arr2(&[[1.0, 0.0], [0.0, 2.0], [4.0, 4.0]]);
+
+        let cancel_flag = AtomicBool::new(false);
+        let propagate = || {
+            NdArrayMatrix::multiply_cancellable(
+                &graph,
+                vectors.view(),
+                MarkovType::Left,
+                2,
+                None,
+                &cancel_flag,
+            )
+        };
+        let expected = NdArrayMatrix::multiply(&graph, vectors.view(), MarkovType::Left, 2);
+        assert_eq!(propagate().unwrap(), expected);
+
+        cancel_flag.store(true, Ordering::Relaxed);
+        assert!(matches!(propagate(), Err(CleoraError::Cancelled)));
+    }
+
+    #[test]
+    fn unnormalized_propagation_sums_raw_weights() {
+        let config = Configuration::builder()
+            .columns("user product weight::w")
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let lines = ["u1\tp1\t2", "u1\tp2\t3", "u2\tp1\t1"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+        assert_eq!(graph.entity_ids, vec!["u1", "p1", "p2", "u2"]);
+
+        let vectors: Array2<f32> = Array2::eye(4);
+        let propagated =
+            NdArrayMatrix::multiply(&graph, vectors.view(), MarkovType::Unnormalized, 2);
+        let expected = arr2(&[
+            [0.0, 2.0, 3.0, 0.0],
+            [2.0, 0.0, 0.0, 1.0],
+            [3.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+        ]);
+        assert!(
+            propagated
+                .iter()
+                .zip(expected.iter())
+                .all(|(value, expected)| (value - expected).abs() < 1e-5),
+            "propagated {}",
+            propagated
+        );
+    }
+
+    #[test]
+    fn hub_rows_independent_of_workers() {
+        let config = Configuration::builder()
+            .columns("user product")
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let lines: Vec<String> = (0..10000).map(|i| format!("hub\tp{}", i)).collect();
+        let graph = build_graph_from_iterator(&config, lines.iter().map(String::as_str)).unwrap();
+        assert_eq!(graph.entity_ids[0], "hub");
+
+        let vectors = Array2::from_shape_fn((graph.entity_ids.len(), 3), |(i, j)| {
+            ((i * 7 + j * 3) % 11) as f32 / 11.0
+        });
+        let propagate = |num_workers| {
+            NdArrayMatrix::multiply(&graph, vectors.view(), MarkovType::Left, num_workers)
+        };
+        let propagated = propagate(1);
+        assert_eq!(propagate(4), propagated);
+        let expected_hub = vectors.slice(s![1.., ..]).mean_axis(Axis(0)).unwrap();
+        for (value, expected) in propagated.row(0).iter().zip(&expected_hub) {
+            assert!((value - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn sampled_rows_keep_their_sums() {
+        let config = Configuration::builder()
+            .columns("user product weight::w")
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let lines: Vec<String> = (0..50)
+            .map(|i| format!("hub\tp{}\t{}", i, i % 7 + 1))
+            .collect();
+        let graph = build_graph_from_iterator(&config, lines.iter().map(String::as_str)).unwrap();
+        assert_eq!(graph.entity_ids[0], "hub");
+
+        // One-hot vectors show which neighbors every row propagated from
+        let vectors: Array2<f32> = Array2::eye(graph.entity_ids.len());
+        let propagate = |num_workers| {
+            NdArrayMatrix::multiply_sampled(
+                &graph,
+                vectors.view(),
+                MarkovType::Left,
+                num_workers,
+                5,
+            )
+        };
+        let sampled = propagate(2);
+        let hub = sampled.row(0);
+        assert!(hub.iter().filter(|v| **v > 0.0).count() <= 5);
+        assert!((hub.sum() - 1.0).abs() < 1e-5);
+        // Rows with few neighbors are not sampled
+        let full = NdArrayMatrix::multiply(&graph, vectors.view(), MarkovType::Left, 2);
+        assert_eq!(sampled.slice(s![1.., ..]), full.slice(s![1.., ..]));
+
+        assert_eq!(propagate(2), sampled);
+        assert_eq!(propagate(1), sampled);
     }
 }
@@ -1,12 +1,15 @@
 use itertools::{Itertools, Product};
-use std::hash::Hasher;
+use std::borrow::Cow;
+use std::hash::{BuildHasherDefault, Hasher};
 use std::ops::Range;
 use std::sync::Arc;
 
+use dashmap::DashSet;
+use rustc_hash::FxHasher;
 use smallvec::{IntoIter, SmallVec};
 use twox_hash::XxHash64;
 
-use crate::configuration::Configuration;
+use crate::configuration::{ColumnMerges, Configuration};
 use crate::sparse_matrix_builder::NodeIndexerBuilder;
 
 /// Indicates how many elements in a vector can be placed on Stack (used by smallvec crate). The rest
@@ -17,6 +20,8 @@ pub const SMALL_VECTOR_SIZE: usize = 8;
 pub struct Hyperedge {
     hashes: SmallVec<[u64; SMALL_VECTOR_SIZE]>,
     slices: [Range<u32>; 2],
+    /// Multiplier applied to every edge generated from the hyperedge
+    pub weight: f32,
 }
 
 impl Hyperedge {
@@ -46,66 +51,221 @@ impl Hyperedge {
     }
 }
 
+/// Remembers fingerprints of processed rows, so repeated rows can be skipped.
+/// Fingerprints are kept for the whole build and a distinct row colliding with a seen one is
+/// skipped as well.
+#[derive(Debug, Default)]
+pub struct SeenRows {
+    fingerprints: DashSet<u64, BuildHasherDefault<FxHasher>>,
+}
+
+impl SeenRows {
+    /// Returns `true` if the row was not seen before.
+    pub fn insert(&self, row: &[SmallVec<[&str; SMALL_VECTOR_SIZE]>]) -> bool {
+        let mut hasher = XxHash64::default();
+        for column_values in row {
+            for value in column_values {
+                hasher.write(value.as_bytes());
+                // separators keep ("ab", "c") and ("a", "bc") apart
+                hasher.write_u8(0x1f);
+            }
+            hasher.write_u8(0x1e);
+        }
+        self.fingerprints.insert(hasher.finish())
+    }
+}
+
 pub struct EntityProcessor<'a, S: NodeIndexerBuilder> {
     config: &'a Configuration,
     not_ignored_columns_count: u16,
     node_indexer: Arc<S>,
+    seen_rows: Arc<SeenRows>,
 }
 
 impl<'a, S: NodeIndexerBuilder> EntityProcessor<'a, S> {
-    pub fn new(config: &'a Configuration, node_indexer: Arc<S>) -> EntityProcessor<'a, S> {
-        let not_ignored_columns_count = config.columns.len() as u16;
+    /// `seen_rows` is shared by all processors of the graph, used when `config.dedup_hyperedges` is set.
+    pub fn new(
+        config: &'a Configuration,
+        node_indexer: Arc<S>,
+        seen_rows: Arc<SeenRows>,
+    ) -> EntityProcessor<'a, S> {
+        let not_ignored_columns_count =
+            config.columns.iter().filter(|c| c.is_entity()).count() as u16;
         EntityProcessor {
             config,
             not_ignored_columns_count,
             node_indexer,
+            seen_rows,
         }
     }
 
     /// Every row can create few combinations (cartesian products) which are hashed and provided for sparse matrix creation.
     /// `row` - array of strings such as: ("userId1", "productId1 productId2", "brandId1").
+    /// Returns `None` for skipped duplicated rows and rows decayed to nothing, and an error for rows
+    /// which can't be turned into a hyperedge, e.g. with invalid weight.
     pub fn process_row_and_get_edges(
         &self,
         row: &[SmallVec<[&str; SMALL_VECTOR_SIZE]>],
-    ) -> Hyperedge {
+    ) -> Result<Option<Hyperedge>, String> {
+        // Row has to be validated before any entity of the row gets indexed. Empty values of
+        // complex columns are skipped, a column needs at least one entity.
+        let empty_column = self
+            .config
+            .columns
+            .iter()
+            .zip(row.iter())
+            .find(|(column, values)| {
+                let mut entities = if column.complex {
+                    &values[..]
+                } else {
+                    &values[..values.len().min(1)]
+                }
+                .iter();
+                column.is_entity() && entities.all(|value| column.is_empty_value(value))
+            });
+        if let Some((column, _)) = empty_column {
+            return Err(format!("Empty field {}", column.name));
+        }
+        match self.parse_weight(row)? {
+            Some(weight) => Ok(self.process_weighted_row(row, weight)),
+            None => Ok(None),
+        }
+    }
+
+    /// Same as `process_row_and_get_edges` but with the weight of the row already known, values
+    /// of the weight column are ignored.
+    pub fn process_weighted_row(
+        &self,
+        row: &[SmallVec<[&str; SMALL_VECTOR_SIZE]>],
+        weight: f32,
+    ) -> Option<Hyperedge> {
+        if self.config.dedup_hyperedges && !self.seen_rows.insert(row) {
+            return None;
+        }
+
         let mut hashes: SmallVec<[u64; SMALL_VECTOR_SIZE]> =
             SmallVec::with_capacity(self.not_ignored_columns_count as usize);
+        // Entities are indexed only once the row is known to produce a hyperedge
+        let mut indexed: SmallVec<[(Cow<str>, u8); SMALL_VECTOR_SIZE]> =
+            SmallVec::with_capacity(self.not_ignored_columns_count as usize);
         let mut slices: [Range<u32>; 2] = [0..0, 0..0];
         let mut reflexive_count = 0;
         let mut current_offset = 0u32;
 
-        for (i, column_entities) in row.iter().enumerate() {
-            let column = &self.config.columns[i];
+        let entity_columns = self
+            .config
+            .columns
+            .iter()
+            .zip(row.iter())
+            .filter(|(column, _)| column.is_entity());
+        for (i, (column, column_entities)) in entity_columns.enumerate() {
             let column_id = i as u8;
-            if column.complex {
-                for entity in column_entities {
-                    let hash = hash_entity(entity);
-                    hashes.push(hash);
-                    self.node_indexer.process(hash, entity, column_id);
+            let column_entities = if column.complex {
+                &column_entities[..]
+            } else {
+                &column_entities[..1]
+            };
+            let mut length = 0u32;
+            for entity in column_entities {
+                if column.is_empty_value(entity) {
+                    continue;
                 }
-                let length = column_entities.len() as u32;
-                slices[i] = current_offset..(current_offset + length);
-                if column.reflexive {
-                    // put reflexive column data to the end of the buffers
-                    let reflexive_id = (self.not_ignored_columns_count + reflexive_count) as usize;
-                    slices[reflexive_id] = current_offset..(current_offset + length);
-                    reflexive_count += 1;
+                let entity = column.transform(entity);
+                let entity = match self.config.aliases.get(entity.as_ref()) {
+                    Some(canonical_id) => Cow::Borrowed(canonical_id.as_str()),
+                    None => entity,
+                };
+                let hash = hash_entity(&entity);
+                if self.config.excluded_entities.contains(&hash) {
+                    continue;
                 }
-                current_offset += length;
-            } else {
-                let entity = column_entities.first().unwrap();
-                let hash = hash_entity(entity);
+                let hash = match self.config.column_merges {
+                    ColumnMerges::Separate => hash_column_entity(&entity, column_id),
+                    ColumnMerges::Warn | ColumnMerges::Fail => hash,
+                };
                 hashes.push(hash);
-                self.node_indexer.process(hash, entity, column_id);
-                let length = 1u32;
-                slices[i] = current_offset..(current_offset + length);
-                current_offset += length;
+                indexed.push((entity, column_id));
+                length += 1;
+            }
+            if length == 0 {
+                // Every entity of the column is excluded, the row creates no edges
+                return None;
             }
+            slices[i] = current_offset..(current_offset + length);
+            if column.reflexive {
+                // put reflexive column data to the end of the buffers
+                let reflexive_id = (self.not_ignored_columns_count + reflexive_count) as usize;
+                slices[reflexive_id] = current_offset..(current_offset + length);
+                reflexive_count += 1;
+            }
+            current_offset += length;
+        }
+        for (hash, (entity, column_id)) in hashes.iter().zip(indexed) {
+            self.node_indexer.process(*hash, &entity, column_id);
+        }
+        Some(Hyperedge {
+            hashes,
+            slices,
+            weight,
+        })
+    }
+
+    /// Weight of the row, the WEIGHT column value decayed by the age read from the TIMESTAMP column.
+    /// Rows old enough for their weight to decay below the f32 range contribute nothing, `None`.
+    fn parse_weight(
+        &self,
+        row: &[SmallVec<[&str; SMALL_VECTOR_SIZE]>],
+    ) -> Result<Option<f32>, String> {
+        let mut weight = 1.0f32;
+        let mut decay = 1.0f64;
+        for (column, values) in self.config.columns.iter().zip(row.iter()) {
+            let value = values.first().copied().unwrap_or_default();
+            if column.weight {
+                weight = match value.parse::<f32>() {
+                    Ok(weight) if is_valid_weight(weight) => weight,
+                    _ => {
+                        return Err(format!(
+                            "Invalid weight '{}' in column {}, expected a positive number",
+                            value, column.name
+                        ))
+                    }
+                };
+            } else if column.timestamp {
+                let row_decay = self
+                    .config
+                    .decay
+                    .expect("Decay is configured with a TIMESTAMP column");
+                let timestamp = match value.parse::<f64>() {
+                    Ok(timestamp) if timestamp.is_finite() => timestamp,
+                    _ => {
+                        return Err(format!(
+                            "Invalid timestamp '{}' in column {}, expected a number",
+                            value, column.name
+                        ))
+                    }
+                };
+                decay = row_decay.weight(timestamp);
+            }
+        }
+        let weight = weight as f64 * decay;
+        if weight < f32::MIN_POSITIVE as f64 {
+            return Ok(None);
+        }
+        if weight > f32::MAX as f64 {
+            return Err(
+                "Timestamp too far after the reference time, the weight is out of range"
+                    .to_string(),
+            );
         }
-        Hyperedge { hashes, slices }
+        Ok(Some(weight as f32))
     }
 }
 
+/// Weights have to be positive, otherwise normalization of the graph breaks.
+pub fn is_valid_weight(weight: f32) -> bool {
+    weight.is_finite() && weight > 0.0
+}
+
 #[inline(always)]
 pub fn hash_entity(entity: &str) -> u64 {
     let mut hasher = XxHash64::default();
@@ -113,11 +273,48 @@ pub fn hash_entity(entity: &str) -> u64 {
     hasher.finish()
 }
 
+/// Hash of an entity which is kept apart from equal values of other columns.
+#[inline(always)]
+pub fn hash_column_entity(entity: &str, column_id: u8) -> u64 {
+    let mut hasher = XxHash64::with_seed(column_id as u64 + 1);
+    hasher.write(entity.as_bytes());
+    hasher.finish()
+}
+
 #[cfg(test)]
 mod tests {
     use smallvec::{smallvec, SmallVec};
 
-    use crate::entity::{Hyperedge, SMALL_VECTOR_SIZE};
+    use crate::configuration::Configuration;
+    use crate::entity::{EntityProcessor, Hyperedge, SMALL_VECTOR_SIZE};
+    use crate::sparse_matrix_builder::SyncNodeIndexerBuilder;
+
+    #[test]
+    fn decayed_weights() {
+        let config = Configuration::builder()
+            .columns("timestamp::t x y")
+            .decay_halflife(Some(1.0))
+            .decay_reference_time(Some(1000.0))
+            .build()
+            .unwrap();
+        let processor = EntityProcessor::<SyncNodeIndexerBuilder>::new(
+            &config,
+            Default::default(),
+            Default::default(),
+        );
+        let weight = |timestamp| {
+            let row: Vec<SmallVec<[&str; SMALL_VECTOR_SIZE]>> =
+                vec![smallvec![timestamp], smallvec!["x"], smallvec!["z"]];
+            processor
+                .process_row_and_get_edges(&row)
+                .map(|hyperedge| hyperedge.map(|hyperedge| hyperedge.weight))
+        };
+        assert_eq!(weight("998"), Ok(Some(0.25)));
+        // Valid rows too old for an f32 weight create no edges
+        assert_eq!(weight("0"), Ok(None));
+        assert!(weight("2000").is_err());
+        assert!(weight("inf").is_err());
+    }
 
     #[test]
     fn generate_cartesian_product_hashes() {
@@ -127,7 +324,11 @@ mod tests {
         // column_3: 3 entities
         let slices = [0..2, 2..5];
         let hashes: SmallVec<[u64; SMALL_VECTOR_SIZE]> = smallvec![10, 20, 30, 40, 50];
-        let hyperedge = Hyperedge { hashes, slices };
+        let hyperedge = Hyperedge {
+            hashes,
+            slices,
+            weight: 1.0,
+        };
         let combinations: Vec<_> = hyperedge.edges_iter(0, 1).collect();
         assert_eq!((10, 30), *combinations.get(0).unwrap());
         assert_eq!((10, 40), *combinations.get(1).unwrap());
@@ -0,0 +1,31 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::configuration::ConfigurationError;
+
+/// Errors returned by graph building, instead of panicking on bad input.
+#[derive(Debug, Error)]
+pub enum CleoraError {
+    #[error("Can't read {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("{0}")]
+    Parse(String),
+
+    #[error("{count} entities collided with other entities' hashes, at most {max} allowed")]
+    HashCollisions { count: usize, max: usize },
+
+    #[error("{count} values occurred in more than one column and would be merged into one entity")]
+    ColumnMerges { count: usize },
+
+    #[error("Graph building was cancelled")]
+    Cancelled,
+
+    #[error(transparent)]
+    Configuration(#[from] ConfigurationError),
+}
@@ -1,43 +1,100 @@
 use std::cmp::min;
-use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::hash::Hasher;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use bincode::{deserialize, serialize};
+use crossbeam::channel::{self, RecvTimeoutError};
+use crossbeam::thread as cb_thread;
 use ndarray::{Array1, Array2, ArrayViewMut2, Axis, Ix1, Ix2};
 use numpy::{PyArray, PyArray2, ToPyArray};
-use pyo3::exceptions::PyValueError;
+use pyo3::exceptions::{PyIOError, PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::types::{PyBytes, PyIterator, PyString, PyTuple};
 use rayon::iter::IndexedParallelIterator;
 use rayon::iter::ParallelIterator;
 use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator};
+use rayon::ThreadPoolBuilder;
+use siphasher::sip::SipHasher13;
 
-use crate::configuration::Configuration;
+use crate::configuration::{ColumnMerges, Configuration, ConfigurationError, FileType, OnBadLine};
 use crate::embedding::{MarkovType, NdArrayMatrix};
-use crate::entity::hash_entity;
-use crate::pipeline::{build_graph_from_files, build_graph_from_iterator};
-use crate::sparse_matrix::{create_sparse_matrix_descriptor, SparseMatrix, SparseMatrixDescriptor};
+use crate::entity::{hash_column_entity, hash_entity};
+use crate::error::CleoraError;
+use crate::pipeline::{
+    build_graph_from_fallible_iterator, build_graph_from_files, build_graph_from_iterator,
+    expand_input_paths, STDIN_PATH,
+};
+use crate::sparse_matrix::{SparseMatrix, SparseMatrixDescriptor};
 
 pub mod configuration;
 pub mod embedding;
 pub mod entity;
+pub mod error;
 pub mod pipeline;
 pub mod sparse_matrix;
 pub mod sparse_matrix_builder;
 
+/// Embeddings accepted for propagation, the result keeps the input precision.
+#[derive(FromPyObject)]
+pub enum EmbeddingsArray<'py> {
+    F32(&'py PyArray2<f32>),
+    F64(&'py PyArray2<f64>),
+}
+
 // Methods not exposed to python
 impl SparseMatrix {
     fn markov_propagate<'py>(
         &self,
-        x: &'py PyArray2<f32>,
+        x: EmbeddingsArray<'py>,
         markov_type: MarkovType,
         num_workers: Option<usize>,
-    ) -> &'py PyArray<f32, Ix2> {
-        let array = unsafe { x.as_array() };
+        max_neighbors: Option<usize>,
+        cancel_token: Option<CancelToken>,
+    ) -> PyResult<&'py PyAny> {
         let multiplication_workers: usize = num_workers.unwrap_or_else(num_cpus::get);
-        let propagated = NdArrayMatrix::multiply(self, array, markov_type, multiplication_workers);
-        propagated.to_pyarray(x.py())
+        if max_neighbors == Some(0) {
+            return Err(PyValueError::new_err("max_neighbors has to be positive"));
+        }
+        let cancel_flag = AtomicBool::new(false);
+        let propagated = match x {
+            EmbeddingsArray::F32(x) => {
+                // Other Python threads may write to the array while the GIL is released
+                let array = x.to_owned_array();
+                let propagated =
+                    run_without_gil(x.py(), &cancel_flag, cancel_token.as_ref(), || {
+                        NdArrayMatrix::multiply_cancellable(
+                            self,
+                            array.view(),
+                            markov_type,
+                            multiplication_workers,
+                            max_neighbors,
+                            &cancel_flag,
+                        )
+                    })??;
+                propagated.to_pyarray(x.py()).as_ref()
+            }
+            EmbeddingsArray::F64(x) => {
+                // Other Python threads may write to the array while the GIL is released
+                let array = x.to_owned_array();
+                let propagated =
+                    run_without_gil(x.py(), &cancel_flag, cancel_token.as_ref(), || {
+                        NdArrayMatrix::multiply_cancellable(
+                            self,
+                            array.view(),
+                            markov_type,
+                            multiplication_workers,
+                            max_neighbors,
+                            &cancel_flag,
+                        )
+                    })??;
+                propagated.to_pyarray(x.py()).as_ref()
+            }
+        };
+        Ok(propagated)
     }
 
     pub fn from_rust_iterator<'a>(
@@ -45,102 +102,256 @@ impl SparseMatrix {
         hyperedge_trim_n: usize,
         hyperedges: impl Iterator<Item = &'a str>,
         num_workers: Option<usize>,
-    ) -> Result<SparseMatrix, &'static str> {
-        let columns = configuration::parse_fields(columns).expect("Columns should be valid");
-        let matrix_desc = create_sparse_matrix_descriptor(&columns)?;
-        let config = Configuration {
-            seed: None,
-            columns,
-            matrix_desc,
-            hyperedge_trim_n,
-            num_workers_graph_building: num_workers.unwrap_or_else(|| min(num_cpus::get(), 8)),
-        };
+    ) -> Result<SparseMatrix, CleoraError> {
+        let config = Configuration::builder()
+            .columns(columns)
+            .hyperedge_trim_n(hyperedge_trim_n)
+            .num_workers(Some(num_workers.unwrap_or_else(default_iterator_workers)))
+            .build()?;
 
-        Ok(build_graph_from_iterator(&config, hyperedges))
+        build_graph_from_iterator(&config, hyperedges)
     }
 
-    fn initialize_deterministically_rust(&self, mut vectors: ArrayViewMut2<f32>, seed: i64) {
-        vectors
-            .axis_iter_mut(Axis(0))
-            .into_par_iter()
-            .enumerate()
-            .for_each(|(entity_ix, mut row)| {
-                let entity_id_hash = hash_entity(self.entity_ids[entity_ix].as_str());
-                row.indexed_iter_mut().for_each(|(col_ix, v)| {
-                    let value = init_value(col_ix, entity_id_hash, seed);
-                    *v = value
-                });
+    fn initialize_deterministically_rust(
+        &self,
+        mut vectors: ArrayViewMut2<f32>,
+        seed: i64,
+        distribution: InitDistribution,
+        num_workers: usize,
+    ) {
+        ThreadPoolBuilder::new()
+            .num_threads(num_workers)
+            .build()
+            .unwrap()
+            .install(|| {
+                vectors
+                    .axis_iter_mut(Axis(0))
+                    .into_par_iter()
+                    .enumerate()
+                    .for_each(|(entity_ix, mut row)| {
+                        let entity_id = self.entity_ids[entity_ix].as_str();
+                        // Equal ids of separate columns start from different vectors
+                        let entity_id_hash = if self.descriptor.separate_columns {
+                            hash_column_entity(entity_id, self.column_ids[entity_ix])
+                        } else {
+                            hash_entity(entity_id)
+                        };
+                        row.indexed_iter_mut().for_each(|(col_ix, v)| {
+                            let value = match distribution {
+                                InitDistribution::Uniform => {
+                                    init_value(col_ix, entity_id_hash, seed)
+                                }
+                                InitDistribution::Gaussian => {
+                                    init_gaussian_value(col_ix, entity_id_hash, seed)
+                                }
+                            };
+                            *v = value
+                        });
+                    });
             });
     }
 }
 
 #[pymethods]
 impl SparseMatrix {
-    #[pyo3(signature = (x, num_workers = None))]
+    #[pyo3(signature = (x, num_workers = None, max_neighbors = None, cancel_token = None))]
     pub fn left_markov_propagate<'py>(
         &self,
-        x: &'py PyArray2<f32>,
+        x: EmbeddingsArray<'py>,
         num_workers: Option<usize>,
-    ) -> &'py PyArray<f32, Ix2> {
-        self.markov_propagate(x, MarkovType::Left, num_workers)
+        max_neighbors: Option<usize>,
+        cancel_token: Option<CancelToken>,
+    ) -> PyResult<&'py PyAny> {
+        self.markov_propagate(
+            x,
+            MarkovType::Left,
+            num_workers,
+            max_neighbors,
+            cancel_token,
+        )
     }
 
-    #[pyo3(signature = (x, num_workers = None))]
+    #[pyo3(signature = (x, num_workers = None, max_neighbors = None, cancel_token = None))]
     fn symmetric_markov_propagate<'py>(
         &self,
-        x: &'py PyArray2<f32>,
+        x: EmbeddingsArray<'py>,
         num_workers: Option<usize>,
-    ) -> &'py PyArray<f32, Ix2> {
-        self.markov_propagate(x, MarkovType::Symmetric, num_workers)
+        max_neighbors: Option<usize>,
+        cancel_token: Option<CancelToken>,
+    ) -> PyResult<&'py PyAny> {
+        self.markov_propagate(
+            x,
+            MarkovType::Symmetric,
+            num_workers,
+            max_neighbors,
+            cancel_token,
+        )
+    }
+
+    /// Propagates over the raw edge weights, without normalizing by node degrees.
+    #[pyo3(signature = (x, num_workers = None, max_neighbors = None, cancel_token = None))]
+    fn unnormalized_propagate<'py>(
+        &self,
+        x: EmbeddingsArray<'py>,
+        num_workers: Option<usize>,
+        max_neighbors: Option<usize>,
+        cancel_token: Option<CancelToken>,
+    ) -> PyResult<&'py PyAny> {
+        self.markov_propagate(
+            x,
+            MarkovType::Unnormalized,
+            num_workers,
+            max_neighbors,
+            cancel_token,
+        )
     }
 
     #[staticmethod]
-    #[pyo3(signature = (hyperedges, columns, hyperedge_trim_n = 16, num_workers = None))]
+    #[pyo3(signature = (
+        hyperedges,
+        columns,
+        hyperedge_trim_n = 16,
+        num_workers = None,
+        dedup_hyperedges = false,
+        directed = false,
+        min_entity_count = 1,
+        exclude_entities = None,
+        aliases = None,
+        decay_halflife = None,
+        decay_reference_time = None,
+        max_hash_collisions = None,
+        column_merges = "warn",
+        on_bad_line = "warn",
+        cancel_token = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn from_iterator(
         hyperedges: &PyIterator,
         columns: &str,
         hyperedge_trim_n: usize,
         num_workers: Option<usize>,
+        dedup_hyperedges: bool,
+        directed: bool,
+        min_entity_count: u32,
+        exclude_entities: Option<Vec<String>>,
+        aliases: Option<HashMap<String, String>>,
+        decay_halflife: Option<f64>,
+        decay_reference_time: Option<f64>,
+        max_hash_collisions: Option<usize>,
+        column_merges: &str,
+        on_bad_line: &str,
+        cancel_token: Option<CancelToken>,
     ) -> PyResult<SparseMatrix> {
-        let hyperedges = hyperedges.map(|line| {
-            let line = line.expect("Should be proper line");
-            let line: &PyString = line
-                .downcast()
-                .expect("Iterator elements should be strings");
-            let line = line.to_str().expect("Should be proper UTF-8 string");
-            line
-        });
-        SparseMatrix::from_rust_iterator(columns, hyperedge_trim_n, hyperedges, num_workers)
-            .map_err(PyValueError::new_err)
+        let config = Configuration::builder()
+            .columns(columns)
+            .hyperedge_trim_n(hyperedge_trim_n)
+            .num_workers(Some(num_workers.unwrap_or_else(default_iterator_workers)))
+            .dedup_hyperedges(dedup_hyperedges)
+            .directed(directed)
+            .min_entity_count(min_entity_count)
+            .exclude_entities(exclude_entities.unwrap_or_default())
+            .aliases(aliases.unwrap_or_default())
+            .decay_halflife(decay_halflife)
+            .decay_reference_time(decay_reference_time)
+            .max_hash_collisions(max_hash_collisions)
+            .column_merges(parse_column_merges(column_merges)?)
+            .on_bad_line(parse_on_bad_line(on_bad_line)?)
+            .cancel_flag(cancel_token.map(|token| token.flag).unwrap_or_default())
+            .build()?;
+
+        // An exception raised by the iterator or a signal handler, e.g. KeyboardInterrupt on
+        // Ctrl-C, ends the input and is raised again
+        let py = hyperedges.py();
+        let mut iterator_error = None;
+        let hyperedges = hyperedges
+            .map_while(
+                |line| match line.and_then(|line| py.check_signals().map(|_| line)) {
+                    Ok(line) => Some(line),
+                    Err(err) => {
+                        iterator_error = Some(err);
+                        None
+                    }
+                },
+            )
+            .map(|line| {
+                let line: &PyString = line.downcast().map_err(|err| err.to_string())?;
+                line.to_str().map_err(|err| err.to_string())
+            });
+        let graph = build_graph_from_fallible_iterator(&config, hyperedges);
+        if let Some(err) = iterator_error {
+            return Err(err);
+        }
+        Ok(graph?)
     }
 
     #[staticmethod]
-    #[pyo3(signature = (filepaths, columns, hyperedge_trim_n = 16, num_workers = None))]
+    #[pyo3(signature = (
+        filepaths,
+        columns,
+        hyperedge_trim_n = 16,
+        num_workers = None,
+        dedup_hyperedges = false,
+        directed = false,
+        min_entity_count = 1,
+        exclude_entities = None,
+        aliases = None,
+        decay_halflife = None,
+        decay_reference_time = None,
+        max_hash_collisions = None,
+        column_merges = "warn",
+        on_bad_line = "warn",
+        cancel_token = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn from_files(
+        py: Python,
         filepaths: Vec<String>,
         columns: &str,
         hyperedge_trim_n: usize,
         num_workers: Option<usize>,
+        dedup_hyperedges: bool,
+        directed: bool,
+        min_entity_count: u32,
+        exclude_entities: Option<Vec<String>>,
+        aliases: Option<HashMap<String, String>>,
+        decay_halflife: Option<f64>,
+        decay_reference_time: Option<f64>,
+        max_hash_collisions: Option<usize>,
+        column_merges: &str,
+        on_bad_line: &str,
+        cancel_token: Option<CancelToken>,
     ) -> PyResult<SparseMatrix> {
+        let filepaths = expand_input_paths(filepaths)?;
         for filepath in filepaths.iter() {
-            if !filepath.ends_with(".tsv") {
-                return Err(PyValueError::new_err("Only .tsv files are supported"));
+            if filepath != STDIN_PATH && FileType::from_path(filepath).is_none() {
+                return Err(PyValueError::new_err(
+                    "Only .tsv, .jsonl, .csv files (optionally .gz or .zst compressed) or \"-\" (standard input) are supported",
+                ));
             }
         }
 
-        let columns = configuration::parse_fields(columns).expect("Columns should be valid");
-        let matrix_desc =
-            create_sparse_matrix_descriptor(&columns).map_err(PyValueError::new_err)?;
-
-        let config = Configuration {
-            seed: None,
-            matrix_desc,
-            columns,
-            hyperedge_trim_n,
-            // TODO consider limiting to some maximum no of workers
-            num_workers_graph_building: num_workers.unwrap_or_else(num_cpus::get),
-        };
-        Ok(build_graph_from_files(&config, filepaths))
+        // TODO consider limiting to some maximum no of workers
+        let cancel_flag: Arc<AtomicBool> = Default::default();
+        let config = Configuration::builder()
+            .columns(columns)
+            .hyperedge_trim_n(hyperedge_trim_n)
+            .num_workers(num_workers)
+            .dedup_hyperedges(dedup_hyperedges)
+            .directed(directed)
+            .min_entity_count(min_entity_count)
+            .exclude_entities(exclude_entities.unwrap_or_default())
+            .aliases(aliases.unwrap_or_default())
+            .decay_halflife(decay_halflife)
+            .decay_reference_time(decay_reference_time)
+            .max_hash_collisions(max_hash_collisions)
+            .column_merges(parse_column_merges(column_merges)?)
+            .on_bad_line(parse_on_bad_line(on_bad_line)?)
+            .cancel_flag(cancel_flag.clone())
+            .build()?;
+        let graph = run_without_gil(py, &cancel_flag, cancel_token.as_ref(), || {
+            build_graph_from_files(&config, filepaths)
+        })?;
+        Ok(graph?)
     }
 
     fn get_entity_column_mask<'py>(
@@ -171,16 +382,36 @@ impl SparseMatrix {
         Array1::from_vec(entity_degrees).to_pyarray(py)
     }
 
-    #[pyo3(signature = (feature_dim, seed = 0))]
+    /// Number of times every entity occurred in the input, e.g. for popularity debiasing.
+    #[getter]
+    fn entity_counts<'py>(&self, py: Python<'py>) -> &'py PyArray<u32, Ix1> {
+        let entity_counts: Vec<u32> = self.entities.par_iter().map(|e| e.occurrence).collect();
+        Array1::from_vec(entity_counts).to_pyarray(py)
+    }
+
+    #[pyo3(signature = (feature_dim, seed = 0, num_workers = None, distribution = "uniform"))]
     fn initialize_deterministically<'py>(
         &self,
         py: Python<'py>,
         feature_dim: usize,
         seed: i64,
-    ) -> &'py PyArray<f32, Ix2> {
+        num_workers: Option<usize>,
+        distribution: &str,
+    ) -> PyResult<&'py PyArray<f32, Ix2>> {
+        let distribution = match distribution {
+            "uniform" => InitDistribution::Uniform,
+            "gaussian" => InitDistribution::Gaussian,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown distribution {}, expected uniform or gaussian",
+                    distribution
+                )))
+            }
+        };
         let mut vectors = Array2::zeros([self.entity_ids.len(), feature_dim]);
-        self.initialize_deterministically_rust(vectors.view_mut(), seed);
-        vectors.to_pyarray(py)
+        let num_workers = num_workers.unwrap_or_else(num_cpus::get);
+        self.initialize_deterministically_rust(vectors.view_mut(), seed, distribution, num_workers);
+        Ok(vectors.to_pyarray(py))
     }
 
     // Stuff needed for pickle to work (new, getstate, setstate)
@@ -194,6 +425,9 @@ impl SparseMatrix {
                     col_a_name: "".to_string(),
                     col_b_id: 0,
                     col_b_name: "".to_string(),
+                    directed: false,
+                    skip_self_loops: false,
+                    separate_columns: false,
                 },
                 entity_ids: vec![],
                 entities: vec![],
@@ -206,36 +440,254 @@ impl SparseMatrix {
     }
 
     pub fn __getstate__(&self, py: Python) -> PyResult<PyObject> {
-        Ok(PyBytes::new(py, &serialize(self).unwrap()).to_object(py))
+        let mut state = PICKLE_HEADER.to_vec();
+        state.extend(PICKLE_VERSION.to_le_bytes());
+        state.extend(serialize(self).unwrap());
+        Ok(PyBytes::new(py, &state).to_object(py))
     }
 
     pub fn __setstate__(&mut self, py: Python, state: PyObject) -> PyResult<()> {
-        match state.extract::<&PyBytes>(py) {
-            Ok(s) => {
-                let sm: SparseMatrix = deserialize(s.as_bytes()).unwrap();
-                *self = sm;
-                Ok(())
+        let state = state.extract::<&PyBytes>(py)?.as_bytes();
+        let versioned = state
+            .strip_prefix(PICKLE_HEADER)
+            .filter(|s| s.len() >= 4)
+            .map(|s| s.split_at(4));
+        let sm = match versioned {
+            Some((version, sm)) if version == PICKLE_VERSION.to_le_bytes() => sm,
+            _ => return Err(PyValueError::new_err(
+                "SparseMatrix was pickled by an incompatible pycleora version, rebuild the graph",
+            )),
+        };
+        *self = deserialize(sm)
+            .map_err(|e| PyValueError::new_err(format!("Invalid SparseMatrix pickle: {}", e)))?;
+        Ok(())
+    }
+}
+
+/// Cancels graph building or propagation from any thread, e.g. once a request of an embedding
+/// service times out. Signals are only handled on the main thread, so they can't do that. The
+/// cancelled call raises `RuntimeError`, a token stays cancelled.
+#[pyclass(name = "CancelToken", module = "cleora")]
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+#[pymethods]
+impl CancelToken {
+    #[new]
+    fn new() -> Self {
+        Default::default()
+    }
+
+    fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    #[getter]
+    fn cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
+/// Prefix of pickled [`SparseMatrix`] states, older versions pickled the bare matrix
+const PICKLE_HEADER: &[u8] = b"pycleora";
+
+/// Bumped whenever the serialized layout of [`SparseMatrix`] changes, e.g. new descriptor or
+/// entity fields. Pickles of other versions can't be loaded.
+const PICKLE_VERSION: u32 = 2;
+
+/// How often pending signals and the cancel token are checked while a job runs without the GIL
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Runs `job` on a separate thread without holding the GIL, so other Python threads keep running.
+/// Meanwhile pending signals are checked: once a signal handler raises an exception, e.g.
+/// KeyboardInterrupt on Ctrl-C, `cancel_flag` is set and the exception is raised after the job
+/// stopped. `cancel_flag` is set as well once `cancel_token` is cancelled.
+fn run_without_gil<T, F>(
+    py: Python,
+    cancel_flag: &AtomicBool,
+    cancel_token: Option<&CancelToken>,
+    job: F,
+) -> PyResult<T>
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    cb_thread::scope(|s| {
+        // Dropped once the job finished, which wakes up the waiting thread
+        let (done_s, done_r) = channel::bounded::<()>(0);
+        let handle = s.spawn(move |_| {
+            let _done_s = done_s;
+            job()
+        });
+        let mut signal_error = None;
+        while let Err(RecvTimeoutError::Timeout) =
+            py.allow_threads(|| done_r.recv_timeout(SIGNAL_CHECK_INTERVAL))
+        {
+            if cancel_token.map_or(false, CancelToken::cancelled) {
+                cancel_flag.store(true, Ordering::Relaxed);
+            }
+            if signal_error.is_none() {
+                if let Err(err) = py.check_signals() {
+                    cancel_flag.store(true, Ordering::Relaxed);
+                    signal_error = Some(err);
+                }
             }
-            Err(e) => Err(e),
+        }
+        let result = handle
+            .join()
+            .unwrap_or_else(|panic| panic::resume_unwind(panic));
+        signal_error.map_or(Ok(result), Err)
+    })
+    .expect("Job thread was joined")
+}
+
+/// Iterator input is consumed by a single thread, more graph building workers wouldn't keep up
+fn default_iterator_workers() -> usize {
+    min(num_cpus::get(), 8)
+}
+
+fn parse_column_merges(column_merges: &str) -> PyResult<ColumnMerges> {
+    match column_merges {
+        "warn" => Ok(ColumnMerges::Warn),
+        "fail" => Ok(ColumnMerges::Fail),
+        "separate" => Ok(ColumnMerges::Separate),
+        _ => Err(PyValueError::new_err(format!(
+            "Unknown column merges {}, expected warn, fail or separate",
+            column_merges
+        ))),
+    }
+}
+
+fn parse_on_bad_line(on_bad_line: &str) -> PyResult<OnBadLine> {
+    match on_bad_line {
+        "skip" => Ok(OnBadLine::Skip),
+        "warn" => Ok(OnBadLine::Warn),
+        "fail" => Ok(OnBadLine::Fail),
+        _ => Err(PyValueError::new_err(format!(
+            "Unknown on_bad_line {}, expected skip, warn or fail",
+            on_bad_line
+        ))),
+    }
+}
+
+impl From<ConfigurationError> for PyErr {
+    fn from(error: ConfigurationError) -> Self {
+        PyValueError::new_err(error.to_string())
+    }
+}
+
+impl From<CleoraError> for PyErr {
+    fn from(error: CleoraError) -> Self {
+        match error {
+            CleoraError::Io { .. } => PyIOError::new_err(error.to_string()),
+            CleoraError::Parse(_)
+            | CleoraError::HashCollisions { .. }
+            | CleoraError::ColumnMerges { .. }
+            | CleoraError::Configuration(_) => PyValueError::new_err(error.to_string()),
+            CleoraError::Cancelled => PyRuntimeError::new_err(error.to_string()),
         }
     }
 }
 
+/// Distribution of the initial vectors, every value depends only on the entity, dimension and seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitDistribution {
+    /// Uniform in (-1, 1)
+    Uniform,
+
+    /// Standard normal, from two uniform values with the Box-Muller transform
+    Gaussian,
+}
+
+fn init_gaussian_value(col: usize, hsh: u64, fixed_random_value: i64) -> f32 {
+    // uniform values in (0, 1), the second one from a different hash of the entity
+    let u1 = (init_value(col, hsh, fixed_random_value) + 1.0) / 2.0;
+    let u2 = (init_value(col, hsh.rotate_left(32), fixed_random_value) + 1.0) / 2.0;
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
 fn init_value(col: usize, hsh: u64, fixed_random_value: i64) -> f32 {
     let hash = |num: i64| {
-        let mut hasher = DefaultHasher::new();
-        hasher.write_i64(num);
+        // SipHash-1-3 with zero keys, the DefaultHasher of released versions, from a crate so its
+        // output doesn't change with Rust versions. Fixed byte order, so vectors are identical on
+        // every architecture.
+        let mut hasher = SipHasher13::new();
+        hasher.write(&num.to_le_bytes());
         hasher.finish() as i64
     };
 
     const MAX_HASH_I64: i64 = 8 * 1024 * 1024;
     const MAX_HASH_F32: f32 = MAX_HASH_I64 as f32;
-    ((hash((hsh as i64) + (col as i64) + fixed_random_value) % MAX_HASH_I64) as f32) / MAX_HASH_F32
+    let num = (hsh as i64)
+        .wrapping_add(col as i64)
+        .wrapping_add(fixed_random_value);
+    ((hash(num) % MAX_HASH_I64) as f32) / MAX_HASH_F32
 }
 
 #[pymodule]
 #[pyo3(name = "pycleora")]
 fn pycleora(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<SparseMatrix>()?;
+    m.add_class::<CancelToken>()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use pyo3::Python;
+
+    use crate::entity::hash_entity;
+    use crate::{init_gaussian_value, init_value, run_without_gil, CancelToken};
+
+    #[test]
+    fn cancel_token_from_worker_thread() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let token = CancelToken::default();
+            let cancel_flag = AtomicBool::new(false);
+            let worker = {
+                let token = token.clone();
+                thread::spawn(move || token.cancel())
+            };
+            // The job ends only once the token cancelled it
+            run_without_gil(py, &cancel_flag, Some(&token), || {
+                let start = Instant::now();
+                while !cancel_flag.load(Ordering::Relaxed) {
+                    assert!(
+                        start.elapsed() < Duration::from_secs(10),
+                        "Job wasn't cancelled"
+                    );
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+            .unwrap();
+            worker.join().unwrap();
+            assert!(token.cancelled());
+        });
+    }
+
+    /// Golden values, they have to be the same on every architecture and compiler version.
+    #[test]
+    fn initialization_is_reproducible() {
+        let hsh = hash_entity("product_1");
+        assert_eq!(hsh, 183204633337376209);
+
+        let values: Vec<f32> = (0..4).map(|col| init_value(col, hsh, 0)).collect();
+        assert_eq!(
+            values,
+            vec![-0.39186728, -0.9579283, -0.60521305, -0.91025245]
+        );
+
+        let gaussian: Vec<f32> = (0..4).map(|col| init_gaussian_value(col, hsh, 7)).collect();
+        assert_eq!(
+            gaussian,
+            vec![-0.755808, -0.10730034, -0.9476629, 0.99391615]
+        );
+    }
+}
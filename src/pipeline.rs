@@ -1,6 +1,10 @@
+use std::cell::Cell;
 use std::cmp::min;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -8,12 +12,18 @@ use crossbeam::channel;
 use crossbeam::channel::{Receiver, Sender};
 use crossbeam::thread as cb_thread;
 use crossbeam::thread::{Scope, ScopedJoinHandle};
+use flate2::read::MultiGzDecoder;
 use itertools::Itertools;
-use log::{error, info, warn};
-use smallvec::SmallVec;
+use log::{info, warn};
+use serde_json::Value;
+use smallvec::{smallvec, SmallVec};
+use zstd::stream::read::Decoder as ZstdDecoder;
 
-use crate::configuration::Configuration;
-use crate::entity::{EntityProcessor, Hyperedge, SMALL_VECTOR_SIZE};
+use crate::configuration::{
+    Column, ColumnMerges, Configuration, FileType, OnBadLine, DEFAULT_COMPLEX_DELIMITER,
+};
+use crate::entity::{is_valid_weight, EntityProcessor, Hyperedge, SeenRows, SMALL_VECTOR_SIZE};
+use crate::error::CleoraError;
 use crate::sparse_matrix::SparseMatrix;
 use crate::sparse_matrix_builder::NodeIndexerBuilder;
 use crate::sparse_matrix_builder::{
@@ -21,10 +31,22 @@ use crate::sparse_matrix_builder::{
     SyncNodeIndexerBuilder,
 };
 
+/// Input path standing for standard input, so graphs can be built at the end of a shell pipeline.
+pub const STDIN_PATH: &str = "-";
+
 pub fn build_graph_from_iterator<'a>(
     config: &Configuration,
     hyperedges: impl Iterator<Item = &'a str>,
-) -> SparseMatrix {
+) -> Result<SparseMatrix, CleoraError> {
+    build_graph_from_fallible_iterator(config, hyperedges.map(Ok))
+}
+
+/// Same as [`build_graph_from_iterator`], for inputs which can fail to provide a line, e.g. one
+/// which is not valid UTF-8. Such lines are `Err` with the reason and are handled as bad lines.
+pub fn build_graph_from_fallible_iterator<'a>(
+    config: &Configuration,
+    hyperedges: impl Iterator<Item = Result<&'a str, String>>,
+) -> Result<SparseMatrix, CleoraError> {
     cb_thread::scope(|s| {
         let (hyperedges_s, hyperedges_r) = channel::bounded(64 * config.num_workers_graph_building);
 
@@ -36,50 +58,198 @@ pub fn build_graph_from_iterator<'a>(
             .into_iter()
             .map(|h| h.join().unwrap())
             .collect_vec();
-        SparseMatrixBuffersReducer::new(node_indexer, buffers, config.num_workers_graph_building)
-            .reduce()
+        let node_indexer = finish_node_indexer(config, node_indexer?)?;
+        Ok(SparseMatrixBuffersReducer::new(
+            node_indexer,
+            buffers,
+            config.num_workers_graph_building,
+        )
+        .reduce())
     })
     .expect("All work in thread scope finished")
 }
 
 fn make_producer_from_iterator<'a>(
     config: &Configuration,
-    hyperedges: impl Iterator<Item = &'a str>,
+    hyperedges: impl Iterator<Item = Result<&'a str, String>>,
     hyperedges_s: Sender<Hyperedge>,
-) -> NodeIndexer {
+) -> Result<NodeIndexer, CleoraError> {
     let node_indexer_builder: Arc<SyncNodeIndexerBuilder> = Default::default();
-    let entity_processor = EntityProcessor::new(config, node_indexer_builder.clone());
-    for line in hyperedges {
-        consume_line(config, &hyperedges_s, &entity_processor, line);
+    let entity_processor =
+        EntityProcessor::new(config, node_indexer_builder.clone(), Default::default());
+    let bad_lines = BadLines::new(config);
+    for (line_number, line) in hyperedges.enumerate() {
+        if config.is_cancelled() {
+            return Err(CleoraError::Cancelled);
+        }
+        match line {
+            Ok(line) => {
+                if let Err(message) = consume_line(config, &hyperedges_s, &entity_processor, line) {
+                    bad_lines.report(message, line)?;
+                }
+            }
+            Err(message) => bad_lines.report(message, format_args!("#{}", line_number + 1))?,
+        }
     }
+    log_bad_lines(bad_lines.count());
     drop(entity_processor);
     let node_indexer_builder =
         Arc::try_unwrap(node_indexer_builder).expect("All other references should be dropped");
-    node_indexer_builder.finish()
+    Ok(node_indexer_builder.finish())
+}
+
+/// Counts malformed lines of the inputs read by a producer and applies [`OnBadLine`] to them.
+struct BadLines<'a> {
+    config: &'a Configuration,
+    count: Cell<u64>,
 }
 
+impl<'a> BadLines<'a> {
+    fn new(config: &'a Configuration) -> Self {
+        BadLines {
+            config,
+            count: Cell::new(0),
+        }
+    }
+
+    /// Fails only with [`OnBadLine::Fail`], the line is skipped otherwise.
+    fn report(&self, message: impl Display, line: impl Display) -> Result<(), CleoraError> {
+        self.count.set(self.count.get() + 1);
+        match self.config.on_bad_line {
+            OnBadLine::Skip => Ok(()),
+            OnBadLine::Warn => {
+                warn!("{}. The line [{}] is skipped.", message, line);
+                Ok(())
+            }
+            OnBadLine::Fail => Err(CleoraError::Parse(format!(
+                "{}. The line [{}] is malformed.",
+                message, line
+            ))),
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.count.get()
+    }
+}
+
+/// Logs the number of skipped lines, once all producers finished.
+fn log_bad_lines(count: u64) {
+    if count > 0 {
+        warn!("{} malformed lines were skipped", count);
+    }
+}
+
+/// Returns a message describing why the line is malformed, the line is skipped then.
 fn consume_line<S: NodeIndexerBuilder>(
     config: &Configuration,
     hyperedges_s: &Sender<Hyperedge>,
     entity_processor: &EntityProcessor<S>,
     line: &str,
-) {
-    let row = parse_tsv_line(line);
+) -> Result<(), String> {
+    let row = parse_tsv_line(line, &config.columns);
+    consume_row(config, hyperedges_s, entity_processor, &row)
+}
+
+fn consume_json_line<S: NodeIndexerBuilder>(
+    config: &Configuration,
+    hyperedges_s: &Sender<Hyperedge>,
+    entity_processor: &EntityProcessor<S>,
+    line: &str,
+) -> Result<(), String> {
+    let values = parse_json_line(line, &config.columns)?;
+    let row: Vec<SmallVec<[&str; SMALL_VECTOR_SIZE]>> = values
+        .iter()
+        .map(|column_values| column_values.iter().map(|v| v.as_str()).collect())
+        .collect();
+    consume_row(config, hyperedges_s, entity_processor, &row)
+}
+
+fn consume_row<S: NodeIndexerBuilder>(
+    config: &Configuration,
+    hyperedges_s: &Sender<Hyperedge>,
+    entity_processor: &EntityProcessor<S>,
+    row: &[SmallVec<[&str; SMALL_VECTOR_SIZE]>],
+) -> Result<(), String> {
     let line_col_num = row.len();
-    if line_col_num == config.columns.len() {
-        let hyperedge = entity_processor.process_row_and_get_edges(&row);
-        hyperedges_s.send(hyperedge).unwrap();
-    } else {
-        warn!(
-            "Wrong number of columns (expected: {}, provided: {}). The line [{}] is skipped.",
+    if line_col_num != config.columns.len() {
+        return Err(format!(
+            "Wrong number of columns (expected: {}, provided: {})",
             config.columns.len(),
-            line_col_num,
-            line
-        );
+            line_col_num
+        ));
+    }
+    if let Some(hyperedge) = entity_processor.process_row_and_get_edges(row)? {
+        hyperedges_s.send(hyperedge).unwrap();
+    }
+    Ok(())
+}
+
+/// Expand directories and shell-style glob patterns (e.g. `events/date=2024-*/part-*.tsv`) into
+/// input files. Directories are expanded to the supported files they contain, not recursively.
+/// Other paths, including [`STDIN_PATH`] and existing files with pattern characters in their
+/// names, are kept as they are. Fails for directories and patterns without any input files.
+pub fn expand_input_paths(paths: Vec<String>) -> Result<Vec<String>, CleoraError> {
+    let mut expanded = Vec::with_capacity(paths.len());
+    for path in paths {
+        if path != STDIN_PATH && Path::new(&path).is_dir() {
+            let entries = fs::read_dir(&path).map_err(|source| CleoraError::Io {
+                path: path.clone(),
+                source,
+            })?;
+            let mut files = Vec::new();
+            for entry in entries {
+                let entry_path = entry
+                    .map_err(|source| CleoraError::Io {
+                        path: path.clone(),
+                        source,
+                    })?
+                    .path();
+                let entry_path = entry_path.to_string_lossy().into_owned();
+                if FileType::from_path(&entry_path).is_some() && Path::new(&entry_path).is_file() {
+                    files.push(entry_path);
+                }
+            }
+            if files.is_empty() {
+                return Err(CleoraError::Parse(format!(
+                    "No supported input files in directory '{}'",
+                    path
+                )));
+            }
+            files.sort();
+            expanded.extend(files);
+        } else if path.contains(['*', '?', '['].as_ref()) && !Path::new(&path).exists() {
+            let matches = glob::glob(&path)
+                .map_err(|e| CleoraError::Parse(format!("Invalid pattern '{}': {}", path, e)))?;
+            let mut found = false;
+            for entry in matches {
+                let entry = entry.map_err(|e| CleoraError::Io {
+                    path: e.path().to_string_lossy().into_owned(),
+                    source: e.into(),
+                })?;
+                if entry.is_file() {
+                    expanded.push(entry.to_string_lossy().into_owned());
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(CleoraError::Parse(format!(
+                    "No input files match '{}'",
+                    path
+                )));
+            }
+        } else {
+            expanded.push(path);
+        }
     }
+    Ok(expanded)
 }
 
-pub fn build_graph_from_files(config: &Configuration, input_files: Vec<String>) -> SparseMatrix {
+/// Fails when an input can't be opened or read, malformed lines are only skipped.
+pub fn build_graph_from_files(
+    config: &Configuration,
+    input_files: Vec<String>,
+) -> Result<SparseMatrix, CleoraError> {
     let processing_worker_num = config.num_workers_graph_building;
     cb_thread::scope(|s| {
         let (hyperedges_s, hyperedges_r) = channel::bounded(processing_worker_num * 64);
@@ -92,6 +262,7 @@ pub fn build_graph_from_files(config: &Configuration, input_files: Vec<String>)
             .into_iter()
             .map(|h| h.join().unwrap())
             .collect_vec();
+        let node_indexer = finish_node_indexer(config, node_indexer?)?;
 
         let merging_start_time = Instant::now();
         let result =
@@ -100,7 +271,7 @@ pub fn build_graph_from_files(config: &Configuration, input_files: Vec<String>)
             "Merging finished in {} sec",
             merging_start_time.elapsed().as_secs()
         );
-        result
+        Ok(result)
     })
     .expect("Threads finished work")
 }
@@ -110,7 +281,7 @@ fn make_producer_from_files<'c: 'e, 'e: 's, 's>(
     input_files: &'c Vec<String>,
     s: &'s Scope<'e>,
     hyperedges_s: Sender<Hyperedge>,
-) -> NodeIndexer {
+) -> Result<NodeIndexer, CleoraError> {
     let (files_s, files_r) = channel::unbounded();
 
     for input in input_files {
@@ -122,34 +293,78 @@ fn make_producer_from_files<'c: 'e, 'e: 's, 's>(
     let file_reading_worker_num = min(max_file_reading_worker_num, input_files.len());
 
     let log_every_n = 10000;
+    // Set once a producer fails, so the other ones stop reading as well
+    let stop: Arc<AtomicBool> = Default::default();
 
     if file_reading_worker_num == 1 {
         let node_indexer_builder: Arc<SyncNodeIndexerBuilder> = Default::default();
-        let entity_processor = EntityProcessor::new(config, node_indexer_builder.clone());
-        consume_files(config, hyperedges_s, files_r, log_every_n, entity_processor);
+        let entity_processor =
+            EntityProcessor::new(config, node_indexer_builder.clone(), Default::default());
+        let bad_lines = consume_files(
+            config,
+            hyperedges_s,
+            files_r,
+            log_every_n,
+            entity_processor,
+            &stop,
+        )?;
+        log_bad_lines(bad_lines);
         let node_indexer_builder =
             Arc::try_unwrap(node_indexer_builder).expect("All other references should be dropped");
-        node_indexer_builder.finish()
+        Ok(node_indexer_builder.finish())
     } else {
         let node_indexer_builder: Arc<AsyncNodeIndexerBuilder> = Default::default();
+        let seen_rows: Arc<SeenRows> = Default::default();
         let producers = (0..file_reading_worker_num)
             .map(|_| {
                 let hyperedges_s = hyperedges_s.clone();
                 let files_r = files_r.clone();
-                let entity_processor = EntityProcessor::new(config, node_indexer_builder.clone());
+                let entity_processor =
+                    EntityProcessor::new(config, node_indexer_builder.clone(), seen_rows.clone());
 
+                let stop = stop.clone();
                 s.spawn(move |_| {
-                    consume_files(config, hyperedges_s, files_r, log_every_n, entity_processor);
+                    let result = consume_files(
+                        config,
+                        hyperedges_s,
+                        files_r,
+                        log_every_n,
+                        entity_processor,
+                        &stop,
+                    );
+                    if result.is_err() {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                    result
                 })
             })
             .collect_vec();
         drop(hyperedges_s); // hyperedges_s got distributed among producers, drop seed object
         drop(files_r);
 
-        producers.into_iter().for_each(|h| h.join().unwrap());
+        let results = producers
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect_vec();
+        let mut bad_lines = 0;
+        let mut error = None;
+        for result in results {
+            match result {
+                Ok(count) => bad_lines += count,
+                // Producers stopped by the failure of another one report it as cancellation
+                Err(CleoraError::Cancelled) if !config.is_cancelled() => {}
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        if let Some(err) = error {
+            return Err(err);
+        }
+        log_bad_lines(bad_lines);
         let node_indexer_builder =
             Arc::try_unwrap(node_indexer_builder).expect("All other references should be dropped");
-        node_indexer_builder.finish()
+        Ok(node_indexer_builder.finish())
     }
 }
 
@@ -159,12 +374,206 @@ fn consume_files<S: NodeIndexerBuilder>(
     files_r: Receiver<&String>,
     log_every_n: u64,
     entity_processor: EntityProcessor<S>,
-) {
+    stop: &AtomicBool,
+) -> Result<u64, CleoraError> {
+    let bad_lines = BadLines::new(config);
     for input in files_r {
-        read_file(input, log_every_n, |line| {
-            consume_line(config, &hyperedges_s, &entity_processor, line);
-        });
+        // Standard input is read as TSV
+        match FileType::from_path(input).unwrap_or(FileType::Tsv) {
+            FileType::Tsv => read_file(input, log_every_n, &bad_lines, |line| {
+                check_stopped(config, stop)?;
+                consume_line(config, &hyperedges_s, &entity_processor, line)
+                    .or_else(|message| bad_lines.report(message, line.trim_end()))
+            })?,
+            FileType::JsonLines => read_file(input, log_every_n, &bad_lines, |line| {
+                check_stopped(config, stop)?;
+                consume_json_line(config, &hyperedges_s, &entity_processor, line)
+                    .or_else(|message| bad_lines.report(message, line.trim_end()))
+            })?,
+            FileType::Csv => read_csv_file(
+                config,
+                &hyperedges_s,
+                &entity_processor,
+                &bad_lines,
+                input,
+                log_every_n,
+                stop,
+            )?,
+        }
+    }
+    Ok(bad_lines.count())
+}
+
+/// Stops a producer once graph building is cancelled or another producer failed.
+fn check_stopped(config: &Configuration, stop: &AtomicBool) -> Result<(), CleoraError> {
+    if config.is_cancelled() || stop.load(Ordering::Relaxed) {
+        Err(CleoraError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+/// Builds a graph from rows or edges pushed one at a time, e.g. read from a message queue or a
+/// database cursor, producing the same [`SparseMatrix`] as [`build_graph_from_iterator`].
+/// Everything is processed on the calling thread.
+pub struct GraphBuilder<'a> {
+    config: &'a Configuration,
+    node_indexer_builder: Arc<SyncNodeIndexerBuilder>,
+    entity_processor: EntityProcessor<'a, SyncNodeIndexerBuilder>,
+    buffer: SparseMatrixBuffer,
+}
+
+impl<'a> GraphBuilder<'a> {
+    pub fn new(config: &'a Configuration) -> Self {
+        let node_indexer_builder: Arc<SyncNodeIndexerBuilder> = Default::default();
+        let entity_processor =
+            EntityProcessor::new(config, node_indexer_builder.clone(), Default::default());
+        GraphBuilder {
+            config,
+            node_indexer_builder,
+            entity_processor,
+            buffer: config.matrix_desc.make_buffer(config.hyperedge_trim_n),
+        }
+    }
+
+    /// Adds a row with one value per configured column, complex values hold entities separated
+    /// by the column delimiter.
+    pub fn add_row(&mut self, fields: &[&str]) -> Result<(), CleoraError> {
+        if fields.len() != self.config.columns.len() {
+            return Err(CleoraError::Parse(format!(
+                "Wrong number of columns (expected: {}, provided: {})",
+                self.config.columns.len(),
+                fields.len()
+            )));
+        }
+        let row: Vec<SmallVec<[&str; SMALL_VECTOR_SIZE]>> = self
+            .config
+            .columns
+            .iter()
+            .zip(fields)
+            .map(|(column, field)| split_field(field, column))
+            .collect();
+        let hyperedge = self
+            .entity_processor
+            .process_row_and_get_edges(&row)
+            .map_err(CleoraError::Parse)?;
+        if let Some(hyperedge) = hyperedge {
+            self.buffer.handle_hyperedge(&hyperedge);
+        }
+        Ok(())
+    }
+
+    /// Adds a single weighted edge between entity `a` of the first column and entity `b` of the
+    /// second one. Only configurations with exactly two entity columns are supported.
+    pub fn add_edge(&mut self, a: &str, b: &str, weight: f32) -> Result<(), CleoraError> {
+        let entity_columns = self.config.columns.iter().filter(|c| c.is_entity()).count();
+        if entity_columns != 2 {
+            return Err(CleoraError::Parse(format!(
+                "Edges can only be added with two entity columns, {} configured",
+                entity_columns
+            )));
+        }
+        if !is_valid_weight(weight) {
+            return Err(CleoraError::Parse(format!(
+                "Invalid weight '{}', expected a positive number",
+                weight
+            )));
+        }
+        let pair = [a, b];
+        let empty_column = self
+            .config
+            .columns
+            .iter()
+            .filter(|column| column.is_entity())
+            .zip(pair.iter())
+            .find(|(column, entity)| column.is_empty_value(entity));
+        if let Some((column, _)) = empty_column {
+            return Err(CleoraError::Parse(format!("Empty field {}", column.name)));
+        }
+        let mut entities = pair.iter().copied();
+        let row: Vec<SmallVec<[&str; SMALL_VECTOR_SIZE]>> = self
+            .config
+            .columns
+            .iter()
+            .map(|column| {
+                if column.is_entity() {
+                    entities.next().into_iter().collect()
+                } else {
+                    SmallVec::new()
+                }
+            })
+            .collect();
+        if let Some(hyperedge) = self.entity_processor.process_weighted_row(&row, weight) {
+            self.buffer.handle_hyperedge(&hyperedge);
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<SparseMatrix, CleoraError> {
+        drop(self.entity_processor);
+        let node_indexer_builder = Arc::try_unwrap(self.node_indexer_builder)
+            .expect("All other references should be dropped");
+        let node_indexer = finish_node_indexer(self.config, node_indexer_builder.finish())?;
+        Ok(SparseMatrixBuffersReducer::new(
+            node_indexer,
+            vec![self.buffer],
+            self.config.num_workers_graph_building,
+        )
+        .reduce())
+    }
+}
+
+/// Reports hash collisions found while indexing and drops rare entities.
+fn finish_node_indexer(
+    config: &Configuration,
+    node_indexer: NodeIndexer,
+) -> Result<NodeIndexer, CleoraError> {
+    let collisions = &node_indexer.hash_collisions;
+    if !collisions.is_empty() {
+        let examples = collisions
+            .iter()
+            .take(10)
+            .map(|(merged, collision)| {
+                format!(
+                    "{} -> {} ({} times)",
+                    merged, collision.entity_id, collision.count
+                )
+            })
+            .join(", ");
+        warn!(
+            "{} entities were merged into other entities with the same hash: {}",
+            collisions.len(),
+            examples
+        );
     }
+    if let Some(max) = config.max_hash_collisions {
+        if collisions.len() > max {
+            return Err(CleoraError::HashCollisions {
+                count: collisions.len(),
+                max,
+            });
+        }
+    }
+    let merges = &node_indexer.column_merges;
+    if !merges.is_empty() {
+        if config.column_merges == ColumnMerges::Fail {
+            return Err(CleoraError::ColumnMerges {
+                count: merges.len(),
+            });
+        }
+        let examples = merges
+            .iter()
+            .take(10)
+            .map(|key| &node_indexer.index_2_entity_id[node_indexer.key_2_index[key]])
+            .join(", ");
+        warn!(
+            "{} values occurred in more than one column and were merged into one entity, \
+             separate column merges keep them apart: {}",
+            merges.len(),
+            examples
+        );
+    }
+    Ok(node_indexer.retain_frequent(config.min_entity_count))
 }
 
 fn make_consumer<'s, 'a: 'a>(
@@ -188,14 +597,137 @@ fn make_consumer<'s, 'a: 'a>(
         .collect()
 }
 
-/// Read file line by line. Pass every valid line to handler for parsing.
-fn read_file<F>(filepath: &str, log_every: u64, mut line_handler: F)
+/// Open an input for reading.
+/// [`STDIN_PATH`] reads standard input instead of a file, `.gz` and `.zst` files are decompressed.
+fn open_input(filepath: &str) -> io::Result<Box<dyn BufRead>> {
+    if filepath == STDIN_PATH {
+        return Ok(Box::new(io::stdin().lock()));
+    }
+
+    let input_file = File::open(filepath)?;
+    // Compressed inputs are decompressed on the fly, based on the file extension
+    if filepath.ends_with(".gz") {
+        let decoder = MultiGzDecoder::new(input_file);
+        Ok(Box::new(BufReader::new(decoder)))
+    } else if filepath.ends_with(".zst") {
+        let decoder = ZstdDecoder::new(input_file)?;
+        Ok(Box::new(BufReader::new(decoder)))
+    } else {
+        Ok(Box::new(BufReader::new(input_file)))
+    }
+}
+
+/// Read file line by line. Pass every valid line to handler for parsing, until it fails.
+fn read_file<F>(
+    filepath: &str,
+    log_every: u64,
+    bad_lines: &BadLines,
+    line_handler: F,
+) -> Result<(), CleoraError>
 where
-    F: FnMut(&str),
+    F: FnMut(&str) -> Result<(), CleoraError>,
 {
-    let input_file = File::open(filepath).expect("Can't open file");
-    let mut buffered = BufReader::new(input_file);
+    let io_error = |source| CleoraError::Io {
+        path: filepath.to_string(),
+        source,
+    };
+    let input = open_input(filepath).map_err(io_error)?;
+    read_lines(input, log_every, bad_lines, line_handler).map_err(|err| match err {
+        LinesError::Io(source) => io_error(source),
+        LinesError::Handler(err) => err,
+    })
+}
+
+/// Failure of [`read_lines`], either reading the source or handling a line.
+enum LinesError {
+    Io(io::Error),
+    Handler(CleoraError),
+}
 
+/// Read a CSV file with a header row, configured columns are looked up by their header names.
+/// Quoted fields can hold delimiters and line breaks.
+fn read_csv_file<S: NodeIndexerBuilder>(
+    config: &Configuration,
+    hyperedges_s: &Sender<Hyperedge>,
+    entity_processor: &EntityProcessor<S>,
+    bad_lines: &BadLines,
+    filepath: &str,
+    log_every: u64,
+    stop: &AtomicBool,
+) -> Result<(), CleoraError> {
+    let io_error = |source| CleoraError::Io {
+        path: filepath.to_string(),
+        source,
+    };
+    let input = open_input(filepath).map_err(io_error)?;
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(input);
+    let headers = reader
+        .headers()
+        .map_err(|err| io_error(err.into()))?
+        .clone();
+    let positions = config
+        .columns
+        .iter()
+        .map(|column| {
+            if column.ignored {
+                return Ok(None);
+            }
+            headers
+                .iter()
+                .position(|header| header == column.name)
+                .map(Some)
+                .ok_or_else(|| {
+                    CleoraError::Parse(format!(
+                        "Column {} is missing in the header of {}",
+                        column.name, filepath
+                    ))
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (record_number, record) in reader.records().enumerate() {
+        check_stopped(config, stop)?;
+        let record_number = record_number as u64 + 1;
+        match record {
+            Ok(record) => {
+                let row: Vec<SmallVec<[&str; SMALL_VECTOR_SIZE]>> = config
+                    .columns
+                    .iter()
+                    .zip(&positions)
+                    .filter_map(|(column, position)| match position {
+                        Some(position) => record
+                            .get(*position)
+                            .map(|field| split_field(field, column)),
+                        None => Some(SmallVec::new()),
+                    })
+                    .collect();
+                if let Err(message) = consume_row(config, hyperedges_s, entity_processor, &row) {
+                    bad_lines.report(message, record.iter().format(","))?;
+                }
+            }
+            Err(err) if err.is_io_error() => return Err(io_error(err.into())),
+            Err(err) => bad_lines.report(err, format_args!("#{}", record_number))?,
+        }
+
+        if record_number % log_every == 0 {
+            info!("Number of records processed: {}", record_number);
+        }
+    }
+    Ok(())
+}
+
+/// Read any buffered source line by line. Pass every valid line to handler for parsing, until it
+/// fails. Lines which are not valid UTF-8 are bad lines, other read errors are returned.
+fn read_lines<R, F>(
+    mut buffered: R,
+    log_every: u64,
+    bad_lines: &BadLines,
+    mut line_handler: F,
+) -> Result<(), LinesError>
+where
+    R: BufRead,
+    F: FnMut(&str) -> Result<(), CleoraError>,
+{
     let mut line_number = 1u64;
     let mut line = String::new();
     loop {
@@ -206,11 +738,14 @@ where
                     break;
                 }
 
-                line_handler(&line);
+                line_handler(&line).map_err(LinesError::Handler)?;
             }
-            Err(err) => {
-                error!("Can't read line number: {}. Error: {}.", line_number, err);
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                bad_lines
+                    .report(err, format_args!("#{}", line_number))
+                    .map_err(LinesError::Handler)?;
             }
+            Err(err) => return Err(LinesError::Io(err)),
         };
 
         // clear to reuse the buffer
@@ -222,10 +757,610 @@ where
 
         line_number += 1;
     }
+    Ok(())
+}
+
+/// Parse a line of JSON and read its columns into a vector for processing.
+/// Arrays provide multiple entities (complex columns), other values are read as a single entity.
+/// Nulls count as missing fields, objects and nested arrays are not entities.
+fn parse_json_line(
+    line: &str,
+    columns: &[Column],
+) -> Result<Vec<SmallVec<[String; SMALL_VECTOR_SIZE]>>, String> {
+    let parsed: Value = serde_json::from_str(line).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let json_to_entity = |value: &Value, column: &Column| match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Number(_) | Value::Bool(_) => Ok(value.to_string()),
+        Value::Null => Err(format!("Missing field {}", column.name)),
+        Value::Array(_) | Value::Object(_) => Err(format!(
+            "Invalid field {}, expected a string, number or an array of them",
+            column.name
+        )),
+    };
+    columns
+        .iter()
+        .map(|c| {
+            if c.ignored {
+                return Ok(SmallVec::new());
+            }
+            let field = parsed
+                .get(c.name.as_str())
+                .ok_or_else(|| format!("Missing field {}", c.name))?;
+            let values: SmallVec<[String; SMALL_VECTOR_SIZE]> = match field {
+                Value::Array(values) => values
+                    .iter()
+                    .map(|value| json_to_entity(value, c))
+                    .collect::<Result<_, _>>()?,
+                value => smallvec![json_to_entity(value, c)?],
+            };
+            if values.is_empty() {
+                return Err(format!("Empty field {}", c.name));
+            }
+            Ok(values)
+        })
+        .collect()
 }
 
 /// Parse a line of TSV and read its columns into a vector for processing.
-fn parse_tsv_line(line: &str) -> Vec<SmallVec<[&str; SMALL_VECTOR_SIZE]>> {
+/// Every field is split with the delimiter of its column.
+fn parse_tsv_line<'a>(
+    line: &'a str,
+    columns: &[Column],
+) -> Vec<SmallVec<[&'a str; SMALL_VECTOR_SIZE]>> {
     let values = line.trim().split('\t');
-    values.map(|c| c.split(' ').collect()).collect()
+    values
+        .enumerate()
+        .map(|(i, c)| match columns.get(i) {
+            Some(column) => split_field(c, column),
+            None => c.split(DEFAULT_COMPLEX_DELIMITER).collect(),
+        })
+        .collect()
+}
+
+/// Split a field into entities by the column delimiter, ignored fields are left empty.
+fn split_field<'a>(field: &'a str, column: &Column) -> SmallVec<[&'a str; SMALL_VECTOR_SIZE]> {
+    if column.ignored {
+        return SmallVec::new();
+    }
+    // Trimmed before splitting, so leading whitespace doesn't produce an empty entity
+    let field = if column.trim { field.trim() } else { field };
+    field.split(column.delimiter).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use crossbeam::channel;
+    use itertools::Itertools;
+    use smallvec::{smallvec, SmallVec};
+
+    use crate::configuration::{parse_fields, Configuration, OnBadLine};
+    use crate::entity::{EntityProcessor, SMALL_VECTOR_SIZE};
+    use crate::error::CleoraError;
+    use crate::pipeline::{
+        build_graph_from_fallible_iterator, build_graph_from_files, build_graph_from_iterator,
+        consume_files, expand_input_paths, parse_json_line, BadLines, GraphBuilder, STDIN_PATH,
+    };
+    use crate::sparse_matrix::SparseMatrix;
+    use crate::sparse_matrix_builder::SyncNodeIndexerBuilder;
+
+    fn entity_ix(graph: &SparseMatrix, entity_id: &str) -> usize {
+        graph
+            .entity_ids
+            .iter()
+            .position(|id| id == entity_id)
+            .expect("Entity is in the graph")
+    }
+
+    /// Left markov value of the edge from `a` to `b`.
+    fn left_markov_value(graph: &SparseMatrix, a: &str, b: &str) -> f32 {
+        let (start, end) = graph.slices[entity_ix(graph, a)];
+        let b = entity_ix(graph, b) as u32;
+        graph.edges[start..end]
+            .iter()
+            .find(|edge| edge.other_entity_ix == b)
+            .expect("Edge is in the graph")
+            .left_markov_value
+    }
+
+    /// Asserts that both graphs have the same edges, with values equal up to f32 rounding of
+    /// sums which were added up in a different order.
+    fn assert_same_edges(graph: &SparseMatrix, expected: &SparseMatrix) {
+        assert_eq!(graph.entity_ids, expected.entity_ids);
+        assert_eq!(graph.slices, expected.slices);
+        let close = |a: f32, b: f32| (a - b).abs() <= 1e-6 * b.abs();
+        for (edge, expected) in graph.edges.iter().zip(&expected.edges) {
+            assert_eq!(edge.other_entity_ix, expected.other_entity_ix);
+            assert!(close(edge.left_markov_value, expected.left_markov_value));
+            assert!(close(
+                edge.symmetric_markov_value,
+                expected.symmetric_markov_value
+            ));
+        }
+    }
+
+    #[test]
+    fn weight_scales_edges() {
+        let config = Configuration::builder()
+            .columns("user product weight::w")
+            .num_workers(Some(2))
+            .on_bad_line(OnBadLine::Fail)
+            .build()
+            .unwrap();
+        let lines = ["u1\tp1\t3", "u1\tp2\t1", "u2\tp2\t0.5"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+
+        assert_eq!(graph.entities[entity_ix(&graph, "u1")].row_sum, 4.0);
+        assert_eq!(graph.entities[entity_ix(&graph, "p2")].row_sum, 1.5);
+        assert_eq!(left_markov_value(&graph, "u1", "p1"), 0.75);
+        assert_eq!(left_markov_value(&graph, "u1", "p2"), 0.25);
+        assert_eq!(left_markov_value(&graph, "p2", "u2"), 0.5 / 1.5);
+
+        for weight in ["0", "-1", "x", "inf", "NaN"] {
+            let line = format!("u1\tp1\t{}", weight);
+            let result = build_graph_from_iterator(&config, [line.as_str()].iter().copied());
+            assert!(result.is_err(), "weight '{}' is rejected", weight);
+        }
+    }
+
+    #[test]
+    fn parse_json_fields() {
+        let columns = parse_fields("user complex::products").unwrap();
+        let row = parse_json_line(r#"{"user": 7, "products": ["a", 1, true]}"#, &columns);
+        let expected: Vec<SmallVec<[String; SMALL_VECTOR_SIZE]>> = vec![
+            smallvec!["7".to_string()],
+            smallvec!["a".to_string(), "1".to_string(), "true".to_string()],
+        ];
+        assert_eq!(row.unwrap(), expected);
+
+        let bad_lines = [
+            r#"{"user": null, "products": ["a"]}"#,
+            r#"{"user": "u", "products": ["a", null]}"#,
+            r#"{"user": "u", "products": []}"#,
+            r#"{"user": "u"}"#,
+            r#"{"user": {"id": "u"}, "products": ["a"]}"#,
+            r#"{"user": "u", "products": [["a", "b"]]}"#,
+            r#"{"user": "u", "products": [{"id": "a"}]}"#,
+            r#"["u", ["a"]]"#,
+            "not json",
+        ];
+        for line in bad_lines {
+            assert!(
+                parse_json_line(line, &columns).is_err(),
+                "{} is rejected",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn dedup_repeated_rows() {
+        let lines = ["a\tb", "a\tb", "a\tc", "a\tb"];
+        let builder = || Configuration::builder().columns("x y").num_workers(Some(2));
+        let config = builder().build().unwrap();
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+        assert_eq!(graph.entities[entity_ix(&graph, "a")].row_sum, 4.0);
+        assert_eq!(left_markov_value(&graph, "a", "b"), 0.75);
+
+        let config = builder().dedup_hyperedges(true).build().unwrap();
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+        assert_eq!(graph.entities[entity_ix(&graph, "a")].row_sum, 2.0);
+        assert_eq!(graph.entities[entity_ix(&graph, "b")].row_sum, 1.0);
+        assert_eq!(left_markov_value(&graph, "a", "b"), 0.5);
+        assert_eq!(graph.entities[entity_ix(&graph, "b")].occurrence, 1);
+    }
+
+    #[test]
+    fn directed_edges() {
+        let config = Configuration::builder()
+            .columns("x y")
+            .num_workers(Some(2))
+            .directed(true)
+            .build()
+            .unwrap();
+        let lines = ["a\tb", "a\tc", "b\tc"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+
+        // One slice per entity, the sink c has an empty one
+        assert_eq!(graph.slices.len(), graph.entity_ids.len());
+        let (start, end) = graph.slices[entity_ix(&graph, "c")];
+        assert_eq!(start, end);
+        assert_eq!(graph.edges.len(), 3);
+
+        assert_eq!(left_markov_value(&graph, "a", "b"), 0.5);
+        assert_eq!(left_markov_value(&graph, "b", "c"), 1.0);
+        // Symmetric values are normalized by weights of edges going into the target
+        let (start, _) = graph.slices[entity_ix(&graph, "b")];
+        assert_eq!(graph.edges[start].symmetric_markov_value, 0.5f32.sqrt());
+    }
+
+    #[test]
+    fn trimming_independent_of_workers() {
+        let lines: Vec<String> = (0..5000)
+            .map(|i| {
+                (0..(i % 13 + 2))
+                    .map(|j| format!("p{}", (i * 7 + j * j) % 400))
+                    .join(" ")
+            })
+            .collect();
+        let graphs: Vec<SparseMatrix> = [1, 3, 8]
+            .iter()
+            .map(|&num_workers| {
+                let config = Configuration::builder()
+                    .columns("complex::reflexive::p")
+                    .hyperedge_trim_n(4)
+                    .num_workers(Some(num_workers))
+                    .build()
+                    .unwrap();
+                build_graph_from_iterator(&config, lines.iter().map(String::as_str)).unwrap()
+            })
+            .collect();
+        for graph in &graphs[1..] {
+            assert_same_edges(graph, &graphs[0]);
+        }
+    }
+
+    #[test]
+    fn trimming_keeps_frequent_nodes() {
+        let config = Configuration::builder()
+            .columns("complex::reflexive::p")
+            .hyperedge_trim_n(2)
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let lines = ["x y", "x y", "x y", "q x r y s"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+
+        let neighbors = |entity_id: &str| {
+            let (start, end) = graph.slices[entity_ix(&graph, entity_id)];
+            graph.edges[start..end]
+                .iter()
+                .map(|edge| graph.entity_ids[edge.other_entity_ix as usize].as_str())
+                .sorted()
+                .collect_vec()
+        };
+        // Only the frequent x and y are connected to the rest of the long row
+        assert_eq!(neighbors("x"), vec!["q", "r", "s", "x", "y"]);
+        assert_eq!(neighbors("q"), vec!["x", "y"]);
+        assert_eq!(neighbors("r"), vec!["x", "y"]);
+        assert_eq!(neighbors("s"), vec!["x", "y"]);
+    }
+
+    #[test]
+    fn rare_entities_drop_their_edges() {
+        let config = Configuration::builder()
+            .columns("complex::reflexive::p")
+            .num_workers(Some(2))
+            .min_entity_count(2)
+            .build()
+            .unwrap();
+        let lines = ["a b c", "a b", "a b d e"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+
+        assert_eq!(graph.entity_ids, vec!["a", "b"]);
+        for (start, end) in &graph.slices {
+            let row_sum: f32 = graph.edges[*start..*end]
+                .iter()
+                .map(|edge| edge.left_markov_value)
+                .sum();
+            assert!((row_sum - 1.0).abs() < 1e-6, "row sums to {}", row_sum);
+        }
+        assert_eq!(left_markov_value(&graph, "a", "b"), 0.5);
+    }
+
+    #[test]
+    fn graph_builder_rows_match_iterator() {
+        let config = Configuration::builder()
+            .columns("user complex::product weight::w")
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let lines = ["u1\tp1 p2\t2", "u2\tp2\t1", "u1\tp3 p1\t0.5"];
+        let expected = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+
+        let mut builder = GraphBuilder::new(&config);
+        for line in lines {
+            builder.add_row(&line.split('\t').collect_vec()).unwrap();
+        }
+        assert!(builder.add_row(&["u1", "p1"]).is_err());
+        let graph = builder.finish().unwrap();
+
+        assert_same_edges(&graph, &expected);
+    }
+
+    #[test]
+    fn graph_builder_adds_edges() {
+        let config = Configuration::builder()
+            .columns("ignore::id user product")
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let mut builder = GraphBuilder::new(&config);
+        builder.add_edge("u1", "p1", 3.0).unwrap();
+        builder.add_edge("u1", "p2", 1.0).unwrap();
+        assert!(builder.add_edge("u1", "p3", 0.0).is_err());
+        assert!(builder.add_edge("u1", "p3", f32::NAN).is_err());
+        assert!(builder.add_edge("u1", "", 1.0).is_err());
+        let graph = builder.finish().unwrap();
+
+        assert_eq!(graph.entity_ids, vec!["u1", "p1", "p2"]);
+        assert_eq!(graph.entities[entity_ix(&graph, "u1")].row_sum, 4.0);
+        assert_eq!(left_markov_value(&graph, "u1", "p1"), 0.75);
+        assert_eq!(left_markov_value(&graph, "p2", "u1"), 1.0);
+
+        let config = Configuration::builder()
+            .columns("complex::reflexive::p")
+            .build()
+            .unwrap();
+        let mut builder = GraphBuilder::new(&config);
+        assert!(builder.add_edge("a", "b", 1.0).is_err());
+    }
+
+    #[test]
+    fn excluded_entities_are_skipped() {
+        let config = Configuration::builder()
+            .columns("user complex::product")
+            .num_workers(Some(2))
+            .exclude_entities(["unknown"])
+            .build()
+            .unwrap();
+        let lines = ["u1\tp1 unknown", "unknown\tp2", "u2\tunknown"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+
+        assert_eq!(graph.entity_ids, vec!["u1", "p1"]);
+        assert_eq!(left_markov_value(&graph, "u1", "p1"), 1.0);
+    }
+
+    #[test]
+    fn unreadable_lines_are_bad_lines() {
+        let lines = [Ok("a\tb"), Err("Invalid UTF-8".to_string()), Ok("a\tc")];
+        let builder = || Configuration::builder().columns("x y").num_workers(Some(2));
+        let config = builder().build().unwrap();
+        let graph = build_graph_from_fallible_iterator(&config, lines.iter().cloned()).unwrap();
+        assert_eq!(graph.entity_ids, vec!["a", "b", "c"]);
+
+        let config = builder().on_bad_line(OnBadLine::Fail).build().unwrap();
+        let result = build_graph_from_fallible_iterator(&config, lines.iter().cloned());
+        assert!(matches!(result, Err(CleoraError::Parse(_))));
+    }
+
+    #[test]
+    fn transformed_entities_are_merged() {
+        let config = Configuration::builder()
+            .columns("ignore::id trim::lowercase::user lowercase::complex::product")
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let lines = ["1\t U1 \tP1 p2", "2\tu1\tp1", "3\tU2\tP2"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+
+        assert_eq!(graph.entity_ids, vec!["u1", "p1", "p2", "u2"]);
+        assert_eq!(graph.entities[entity_ix(&graph, "u1")].occurrence, 2);
+        assert_eq!(graph.entities[entity_ix(&graph, "p1")].occurrence, 2);
+        assert_eq!(graph.entities[entity_ix(&graph, "p2")].occurrence, 2);
+    }
+
+    #[test]
+    fn aliases_are_resolved_once() {
+        let config = Configuration::builder()
+            .columns("user lowercase::product")
+            .aliases([("p_old", "p_new"), ("p_new", "p_newest")])
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let lines = ["u1\tP_OLD", "u2\tp_new", "u3\tp_old"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+
+        // Aliases match transformed values and canonical ids are not resolved again
+        assert_eq!(
+            graph.entity_ids,
+            vec!["u1", "p_new", "u2", "p_newest", "u3"]
+        );
+        assert_eq!(graph.entities[entity_ix(&graph, "p_new")].occurrence, 2);
+        assert_eq!(left_markov_value(&graph, "u3", "p_new"), 1.0);
+    }
+
+    /// Writes an input file to the temporary directory, unique for the test process.
+    fn write_input(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("pycleora-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn read_csv_by_header() {
+        let input = write_input(
+            "header.csv",
+            "product,note,user\n\"p1,p2\",\"a, b\nc\",u1\np3,x,u2\np4\n",
+        );
+        let builder = || {
+            Configuration::builder()
+                .columns("user complex[,]::product")
+                .num_workers(Some(2))
+        };
+        let config = builder().build().unwrap();
+        let graph = build_graph_from_files(&config, vec![input.clone()]).unwrap();
+        assert_eq!(graph.entity_ids, vec!["u1", "p1", "p2", "u2", "p3"]);
+        assert_eq!(left_markov_value(&graph, "u2", "p3"), 1.0);
+
+        // The last record is too short
+        let config = builder().on_bad_line(OnBadLine::Fail).build().unwrap();
+        let result = build_graph_from_files(&config, vec![input.clone()]);
+        assert!(matches!(result, Err(CleoraError::Parse(_))));
+
+        let config = Configuration::builder()
+            .columns("user brand")
+            .build()
+            .unwrap();
+        let result = build_graph_from_files(&config, vec![input.clone()]);
+        assert!(matches!(result, Err(CleoraError::Parse(message)) if message.contains("brand")));
+        std::fs::remove_file(input).unwrap();
+    }
+
+    #[test]
+    fn bad_lines_follow_policy() {
+        for on_bad_line in [OnBadLine::Skip, OnBadLine::Warn, OnBadLine::Fail] {
+            let config = Configuration::builder()
+                .columns("x y")
+                .on_bad_line(on_bad_line)
+                .build()
+                .unwrap();
+            let bad_lines = BadLines::new(&config);
+            let result = bad_lines.report("Missing column y", "a");
+            assert_eq!(result.is_err(), on_bad_line == OnBadLine::Fail);
+            assert_eq!(bad_lines.count(), 1);
+        }
+    }
+
+    /// Reads the inputs with a single producer, returning its number of bad lines.
+    fn count_bad_lines(
+        config: &Configuration,
+        inputs: &[String],
+        stop: &AtomicBool,
+    ) -> Result<u64, CleoraError> {
+        let (hyperedges_s, _hyperedges_r) = channel::unbounded();
+        let (files_s, files_r) = channel::unbounded();
+        for input in inputs {
+            files_s.send(input).unwrap();
+        }
+        drop(files_s);
+        let node_indexer_builder: Arc<SyncNodeIndexerBuilder> = Default::default();
+        let entity_processor =
+            EntityProcessor::new(config, node_indexer_builder, Default::default());
+        consume_files(config, hyperedges_s, files_r, 10, entity_processor, stop)
+    }
+
+    #[test]
+    fn bad_lines_are_counted() {
+        let inputs = vec![
+            write_input("bad-1.tsv", "a\tb\nc\na\tc\n"),
+            write_input(
+                "bad-2.jsonl",
+                "{\"x\": \"a\", \"y\": \"d\"}\n{\"x\": \"a\"}\nnot json\n",
+            ),
+        ];
+        let builder = || Configuration::builder().columns("x y");
+        for on_bad_line in [OnBadLine::Skip, OnBadLine::Warn] {
+            let config = builder().on_bad_line(on_bad_line).build().unwrap();
+            let bad_lines = count_bad_lines(&config, &inputs, &AtomicBool::new(false));
+            assert_eq!(bad_lines.unwrap(), 3);
+        }
+        let config = builder().on_bad_line(OnBadLine::Fail).build().unwrap();
+        let result = count_bad_lines(&config, &inputs, &AtomicBool::new(false));
+        assert!(matches!(result, Err(CleoraError::Parse(_))));
+        // Producers stop as soon as another one failed
+        let config = builder().build().unwrap();
+        let result = count_bad_lines(&config, &inputs, &AtomicBool::new(true));
+        assert!(matches!(result, Err(CleoraError::Cancelled)));
+        for input in inputs {
+            std::fs::remove_file(input).unwrap();
+        }
+    }
+
+    #[test]
+    fn failing_reader_fails_graph_building() {
+        let good = (0..20000).map(|i| format!("u{}\tp{}\n", i, i % 7)).join("");
+        let inputs = vec![
+            write_input("good-1.tsv", &good),
+            write_input("failing.tsv", "u1\tp1\nu2\n"),
+            write_input("good-2.tsv", &good),
+        ];
+        let builder = || Configuration::builder().columns("x y").num_workers(Some(4));
+        let config = builder().on_bad_line(OnBadLine::Skip).build().unwrap();
+        let graph = build_graph_from_files(&config, inputs.clone()).unwrap();
+        assert_eq!(graph.entity_ids.len(), 20007);
+
+        // The error of the failing reader is reported, not the stop of the other ones
+        let config = builder().on_bad_line(OnBadLine::Fail).build().unwrap();
+        let result = build_graph_from_files(&config, inputs.clone());
+        assert!(matches!(result, Err(CleoraError::Parse(message)) if message.contains("u2")));
+        for input in inputs {
+            std::fs::remove_file(input).unwrap();
+        }
+    }
+
+    #[test]
+    fn empty_values_are_not_entities() {
+        let builder = || {
+            Configuration::builder()
+                .columns("user complex::product")
+                .num_workers(Some(2))
+                .on_bad_line(OnBadLine::Fail)
+        };
+        let config = builder().build().unwrap();
+        let lines = ["u1\tp1  p2 ", "u2\t p3"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+        assert_eq!(graph.entity_ids, vec!["u1", "p1", "p2", "u2", "p3"]);
+
+        // The whole line is trimmed, so empty values are checked in the middle of it
+        for (columns, line) in [
+            ("user complex::product ignore::note", "u1\t  \tx"),
+            ("user product ignore::note", "u1\t p1\tx"),
+            ("user trim::product ignore::note", "u1\t  \tx"),
+        ] {
+            let config = builder().columns(columns).build().unwrap();
+            let result = build_graph_from_iterator(&config, [line].iter().copied());
+            assert!(matches!(result, Err(CleoraError::Parse(_))), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn no_self_loops_rows_sum_to_one() {
+        let config = Configuration::builder()
+            .columns("complex::reflexive::noselfloops::p")
+            .num_workers(Some(2))
+            .build()
+            .unwrap();
+        let lines = ["x y z", "x y", "z"];
+        let graph = build_graph_from_iterator(&config, lines.iter().copied()).unwrap();
+
+        assert_eq!(graph.entity_ids, vec!["x", "y", "z"]);
+        for (ix, (start, end)) in graph.slices.iter().enumerate() {
+            let edges = &graph.edges[*start..*end];
+            assert!(edges.iter().all(|edge| edge.other_entity_ix as usize != ix));
+            let row_sum: f32 = edges.iter().map(|edge| edge.left_markov_value).sum();
+            assert!((row_sum - 1.0).abs() < 1e-6, "row sums to {}", row_sum);
+        }
+    }
+
+    #[test]
+    fn expand_directories_and_globs() {
+        let dir = std::env::temp_dir().join(format!("pycleora-{}-inputs", std::process::id()));
+        let empty_dir = dir.join("empty");
+        std::fs::create_dir_all(&empty_dir).unwrap();
+        for name in ["b.tsv", "a.jsonl.gz", "c.txt", "d.tsv", "e[1].tsv"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let expanded = expand_input_paths(vec![path(""), STDIN_PATH.to_string()]).unwrap();
+        assert_eq!(
+            expanded,
+            vec![
+                path("a.jsonl.gz"),
+                path("b.tsv"),
+                path("d.tsv"),
+                path("e[1].tsv"),
+                "-".to_string()
+            ]
+        );
+        let mut expanded = expand_input_paths(vec![path("*.tsv")]).unwrap();
+        expanded.sort();
+        assert_eq!(
+            expanded,
+            vec![path("b.tsv"), path("d.tsv"), path("e[1].tsv")]
+        );
+        // Existing files are not patterns, even with pattern characters in their names
+        let expanded = expand_input_paths(vec![path("e[1].tsv")]).unwrap();
+        assert_eq!(expanded, vec![path("e[1].tsv")]);
+        // Paths without patterns are checked only when they are read
+        let expanded = expand_input_paths(vec![path("missing.tsv")]).unwrap();
+        assert_eq!(expanded, vec![path("missing.tsv")]);
+
+        assert!(expand_input_paths(vec![path("*.csv")]).is_err());
+        assert!(expand_input_paths(vec![path("empty")]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
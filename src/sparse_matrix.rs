@@ -3,7 +3,7 @@ use pyo3::pyclass;
 use serde::{Deserialize, Serialize};
 
 pub fn create_sparse_matrix_descriptor(
-    colums: &Vec<Column>,
+    colums: &[Column],
 ) -> Result<SparseMatrixDescriptor, &'static str> {
     let mut matrices_descs = create_sparse_matrices_descriptors(colums);
     if matrices_descs.len() != 1 {
@@ -16,20 +16,32 @@ pub fn create_sparse_matrix_descriptor(
 /// Let's say that we have such columns configuration: complex::a reflexive::complex::b c. This is provided
 /// as `&[Column]` after parsing the config.
 /// The allowed column modifiers are:
-/// - transient - the field is virtual - it is considered during embedding process, no entity is written for the column,
-/// - complex   - the field is composite, containing multiple entity identifiers separated by space,
-/// - reflexive - the field is reflexive, which means that it interacts with itself, additional output file is written for every such field.
+/// - complex      - the field is composite, containing multiple entity identifiers separated by space,
+/// - complex[<c>] - same as complex, with entity identifiers separated by the character `c`,
+/// - reflexive    - the field is reflexive, which means that it interacts with itself, additional output file is written for every such field,
+/// - noselfloops  - entities of a reflexive field are not paired with themselves,
+/// - lowercase    - entity identifiers are lowercased,
+/// - trim         - surrounding whitespace is removed from entity identifiers,
+/// - weight       - the field is a numeric multiplier of the row's edges, not entities,
+/// - timestamp    - the field is the time of the row used to decay its weight, not entities,
+/// - ignore       - the field is skipped.
+///
+/// Only entity fields (not weight, timestamp or ignore) take part in the relations below.
 /// We create sparse matrix for every columns relations (based on column modifiers).
 /// For our example we have:
 /// - sparse matrix for column a and b,
 /// - sparse matrix for column a and c,
 /// - sparse matrix for column b and c,
 /// - sparse matrix for column b and b (reflexive column).
+///
 /// Apart from column names in sparse matrix we provide indices for incoming data. We have 3 columns such as a, b and c
 /// but column b is reflexive so we need to include this column. The result is: (a, b, c, b).
 /// The rule is that every reflexive column is append with the order of occurrence to the end of constructed array.
-pub fn create_sparse_matrices_descriptors(cols: &Vec<Column>) -> Vec<SparseMatrixDescriptor> {
+pub fn create_sparse_matrices_descriptors(cols: &[Column]) -> Vec<SparseMatrixDescriptor> {
     let mut sparse_matrix_builders: Vec<SparseMatrixDescriptor> = Vec::new();
+    // Weight, timestamp and ignored fields hold no entities, so the indices below refer to
+    // entity fields only
+    let cols: Vec<&Column> = cols.iter().filter(|c| c.is_entity()).collect();
     let num_fields = cols.len();
     let mut reflexive_count = 0;
 
@@ -48,12 +60,13 @@ pub fn create_sparse_matrices_descriptors(cols: &Vec<Column>) -> Vec<SparseMatri
             } else if i == j && col_i.reflexive {
                 let new_j = num_fields + reflexive_count;
                 reflexive_count += 1;
-                let sm = SparseMatrixDescriptor::new(
+                let mut sm = SparseMatrixDescriptor::new(
                     i as u8,
                     col_i.name.clone(),
                     new_j as u8,
                     col_j.name.clone(),
                 );
+                sm.skip_self_loops = col_i.no_self_loops;
                 sparse_matrix_builders.push(sm);
             }
         }
@@ -74,6 +87,16 @@ pub struct SparseMatrixDescriptor {
 
     /// Second column name
     pub col_b_name: String,
+
+    /// Edges go only from entities of the first column to entities of the second column
+    pub directed: bool,
+
+    /// Entities of a reflexive column are not paired with themselves
+    pub skip_self_loops: bool,
+
+    /// Equal values of different columns are separate entities, see
+    /// [`crate::configuration::ColumnMerges::Separate`]
+    pub separate_columns: bool,
 }
 
 #[pyclass(name = "SparseMatrix", module = "cleora")]
@@ -85,7 +108,7 @@ pub struct SparseMatrix {
     pub entities: Vec<Entity>,
     pub edges: Vec<Edge>,
     /// Maps entities to its edges
-    /// I-th slice represent edges going out of ith node
+    /// I-th slice represent edges going out of ith node, it's empty for nodes without outgoing edges
     /// Example:
     /// Given slices=[(0, 4), (4, 10), (10, 11)]
     /// edges[0..4] are outgoing edges for entity=0
@@ -98,6 +121,8 @@ pub struct SparseMatrix {
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Entity {
     pub row_sum: f32,
+    /// Number of times the entity occurred in the input
+    pub occurrence: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1,14 +1,17 @@
 use std::cell::RefCell;
 use std::cmp::Reverse;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::BuildHasherDefault;
+use std::mem;
 use std::ptr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use itertools::Itertools;
+use rayon::iter::IndexedParallelIterator;
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::IntoParallelRefIterator;
+use rayon::iter::IntoParallelRefMutIterator;
 use rayon::iter::ParallelDrainFull;
 use rayon::iter::ParallelIterator;
 use rayon::prelude::ParallelSliceMut;
@@ -19,10 +22,14 @@ use smallvec::SmallVec;
 use crate::entity::{Hyperedge, SMALL_VECTOR_SIZE};
 use crate::sparse_matrix::{Edge, Entity, SparseMatrix, SparseMatrixDescriptor};
 
+/// Sums are accumulated in f64. Workers add up different subsets of the rows, so with another
+/// number of workers the sums can differ in the last f64 bits. After rounding to f32 the values
+/// of the final matrix agree up to the last bit, they aren't guaranteed to be bit-identical.
 #[derive(Debug, Default)]
 struct Row {
-    occurrence: u32,
-    row_sum: f32,
+    row_sum: f64,
+    /// Weight of incoming edges, tracked separately from `row_sum` only for directed matrices
+    col_sum: f64,
 }
 
 /// Data locality plays huge role in propagation phase performance
@@ -35,6 +42,54 @@ pub struct NodeIndexer {
     pub index_2_key: Vec<u64>,
     pub index_2_entity_id: Vec<String>,
     pub index_2_column_id: Vec<u8>,
+    /// Number of times the entity occurred in the input
+    pub index_2_count: Vec<u32>,
+    /// Entities merged into an indexed entity with the same hash, by their id
+    pub hash_collisions: HashMap<String, HashCollision>,
+    /// Keys of entities which occurred in more than one column
+    pub column_merges: HashSet<u64, BuildHasherDefault<FxHasher>>,
+}
+
+/// Entity which got the node of a different entity, as their hashes are equal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashCollision {
+    /// Id of the indexed entity
+    pub entity_id: String,
+    /// Number of occurrences merged into the indexed entity
+    pub count: u32,
+}
+
+impl NodeIndexer {
+    /// Drops entities which occurred fewer than `min_count` times, keeping the order of the rest.
+    pub fn retain_frequent(self, min_count: u32) -> NodeIndexer {
+        if min_count <= 1 {
+            return self;
+        }
+        let mut node_indexer = NodeIndexer {
+            hash_collisions: self.hash_collisions,
+            column_merges: self.column_merges,
+            ..Default::default()
+        };
+        let entities = self
+            .index_2_key
+            .into_iter()
+            .zip(self.index_2_entity_id)
+            .zip(self.index_2_column_id)
+            .zip(self.index_2_count);
+        for (((key, entity_id), column_id), count) in entities {
+            if count < min_count {
+                continue;
+            }
+            node_indexer
+                .key_2_index
+                .insert(key, node_indexer.index_2_key.len());
+            node_indexer.index_2_key.push(key);
+            node_indexer.index_2_entity_id.push(entity_id);
+            node_indexer.index_2_column_id.push(column_id);
+            node_indexer.index_2_count.push(count);
+        }
+        node_indexer
+    }
 }
 
 pub trait NodeIndexerBuilder {
@@ -55,6 +110,9 @@ impl Default for SyncNodeIndexerBuilder {
                 index_2_key: vec![],
                 index_2_column_id: vec![],
                 index_2_entity_id: vec![],
+                index_2_count: vec![],
+                hash_collisions: Default::default(),
+                column_merges: Default::default(),
             }),
         }
     }
@@ -64,7 +122,22 @@ impl NodeIndexerBuilder for SyncNodeIndexerBuilder {
     fn process(&self, key: u64, entity_id: &str, column_id: u8) {
         let mut node_indexer = self.node_indexer.borrow_mut();
 
-        if node_indexer.key_2_index.contains_key(&key) {
+        if let Some(index) = node_indexer.key_2_index.get(&key).copied() {
+            node_indexer.index_2_count[index] += 1;
+            if node_indexer.index_2_column_id[index] != column_id {
+                node_indexer.column_merges.insert(key);
+            }
+            if node_indexer.index_2_entity_id[index] != entity_id {
+                let indexed_entity_id = node_indexer.index_2_entity_id[index].clone();
+                node_indexer
+                    .hash_collisions
+                    .entry(entity_id.to_string())
+                    .or_insert_with(|| HashCollision {
+                        entity_id: indexed_entity_id,
+                        count: 0,
+                    })
+                    .count += 1;
+            }
             return;
         }
         let index = node_indexer.key_2_index.len();
@@ -72,6 +145,7 @@ impl NodeIndexerBuilder for SyncNodeIndexerBuilder {
         node_indexer.index_2_key.push(key);
         node_indexer.index_2_entity_id.push(entity_id.to_string());
         node_indexer.index_2_column_id.push(column_id);
+        node_indexer.index_2_count.push(1);
     }
 
     fn finish(self) -> NodeIndexer {
@@ -84,25 +158,46 @@ pub struct IndexedEntity {
     index: usize,
     id: String,
     column_id: u8,
+    count: u32,
 }
 
 #[derive(Debug, Default)]
 pub struct AsyncNodeIndexerBuilder {
     key_2_entity: DashMap<u64, IndexedEntity, BuildHasherDefault<FxHasher>>,
     next_index: AtomicUsize,
+    hash_collisions: DashMap<String, HashCollision>,
+    column_merges: DashSet<u64, BuildHasherDefault<FxHasher>>,
 }
 
 impl NodeIndexerBuilder for AsyncNodeIndexerBuilder {
     fn process(&self, key: u64, entity_id: &str, column_id: u8) {
-        self.key_2_entity.entry(key).or_insert_with(|| {
-            let index = self.next_index.fetch_add(1, Ordering::Relaxed);
-            let id = entity_id.to_string();
-            IndexedEntity {
-                index,
-                id,
-                column_id,
-            }
-        });
+        self.key_2_entity
+            .entry(key)
+            .and_modify(|indexed_entity| {
+                indexed_entity.count += 1;
+                if indexed_entity.column_id != column_id {
+                    self.column_merges.insert(key);
+                }
+                if indexed_entity.id != entity_id {
+                    self.hash_collisions
+                        .entry(entity_id.to_string())
+                        .or_insert_with(|| HashCollision {
+                            entity_id: indexed_entity.id.clone(),
+                            count: 0,
+                        })
+                        .count += 1;
+                }
+            })
+            .or_insert_with(|| {
+                let index = self.next_index.fetch_add(1, Ordering::Relaxed);
+                let id = entity_id.to_string();
+                IndexedEntity {
+                    index,
+                    id,
+                    column_id,
+                    count: 1,
+                }
+            });
     }
 
     fn finish(self) -> NodeIndexer {
@@ -124,14 +219,21 @@ impl NodeIndexerBuilder for AsyncNodeIndexerBuilder {
         unsafe impl Send for PointerU8 {}
         unsafe impl Sync for PointerU8 {}
 
+        #[derive(Copy, Clone)]
+        struct PointerU32(*mut u32);
+        unsafe impl Send for PointerU32 {}
+        unsafe impl Sync for PointerU32 {}
+
         let numel = self.next_index.into_inner();
         let mut index_2_key: Vec<u64> = vec![0; numel];
         let mut index_2_entity_id = vec![String::new(); numel];
         let mut index_2_column_id = vec![0; numel];
+        let mut index_2_count = vec![0; numel];
 
         let index_2_key_ptr = PointerU64(index_2_key.as_mut_ptr());
         let index_2_entity_id_ptr = PointerString(index_2_entity_id.as_mut_ptr());
         let index_2_column_id_ptr = PointerU8(index_2_column_id.as_mut_ptr());
+        let index_2_count_ptr = PointerU32(index_2_count.as_mut_ptr());
 
         let key_2_index = self
             .key_2_entity
@@ -141,11 +243,13 @@ impl NodeIndexerBuilder for AsyncNodeIndexerBuilder {
                     index,
                     id: entity_id,
                     column_id,
+                    count,
                 } = indexed_entity;
                 unsafe {
                     ptr::write(index_2_key_ptr.0.add(index), key);
                     ptr::write(index_2_entity_id_ptr.0.add(index), entity_id);
                     ptr::write(index_2_column_id_ptr.0.add(index), column_id);
+                    ptr::write(index_2_count_ptr.0.add(index), count);
                 }
                 (key, index)
             })
@@ -156,6 +260,9 @@ impl NodeIndexerBuilder for AsyncNodeIndexerBuilder {
             index_2_key,
             index_2_entity_id,
             index_2_column_id,
+            index_2_count,
+            hash_collisions: self.hash_collisions.into_iter().collect(),
+            column_merges: self.column_merges.into_iter().collect(),
         }
     }
 }
@@ -167,6 +274,9 @@ impl SparseMatrixDescriptor {
             col_a_name,
             col_b_id,
             col_b_name,
+            directed: false,
+            skip_self_loops: false,
+            separate_columns: false,
         }
     }
 
@@ -177,6 +287,7 @@ impl SparseMatrixDescriptor {
             hash_2_row: Default::default(),
             hashes_2_edge: Default::default(),
             hyperedge_trim_n,
+            trimmed_hyperedges: Default::default(),
         }
     }
 }
@@ -186,8 +297,18 @@ pub struct SparseMatrixBuffer {
     pub descriptor: SparseMatrixDescriptor,
     pub edge_count: u32,
     hash_2_row: HashMap<u64, Row, BuildHasherDefault<FxHasher>>,
-    hashes_2_edge: HashMap<(u64, u64), f32, BuildHasherDefault<FxHasher>>,
+    hashes_2_edge: HashMap<(u64, u64), f64, BuildHasherDefault<FxHasher>>,
     hyperedge_trim_n: usize,
+    /// Hyperedges with more than `hyperedge_trim_n` nodes in a column, their edges are created
+    /// once the entity counts of the whole input are known
+    trimmed_hyperedges: Vec<TrimmedHyperedge>,
+}
+
+#[derive(Debug)]
+struct TrimmedHyperedge {
+    nodes_a: SmallVec<[u64; SMALL_VECTOR_SIZE]>,
+    nodes_b: SmallVec<[u64; SMALL_VECTOR_SIZE]>,
+    value: f64,
 }
 
 impl SparseMatrixBuffer {
@@ -195,46 +316,79 @@ impl SparseMatrixBuffer {
         let SparseMatrixDescriptor {
             col_a_id, col_b_id, ..
         } = self.descriptor;
-        let total_combinations = hyperedge.edges_num(col_a_id, col_b_id) as u32;
-
-        let mut nodes_a = hyperedge.nodes(col_a_id as usize);
-        let mut nodes_b = hyperedge.nodes(col_b_id as usize);
+        let nodes_a = hyperedge.nodes(col_a_id as usize);
+        let nodes_b = hyperedge.nodes(col_b_id as usize);
+
+        // Without self loops every entity of a reflexive column has one partner less, edges get
+        // larger values so that they still add up to the row sums
+        let self_loop = self.descriptor.skip_self_loops as usize;
+        let partners = nodes_b.len() - self_loop.min(nodes_b.len());
+        let total_combinations = (nodes_a.len() * partners) as u32;
+        if total_combinations == 0 {
+            return;
+        }
 
         for hash in &nodes_a {
-            self.update_row(*hash, nodes_b.len() as u32);
+            self.update_row(*hash, nodes_b.len() as u32, hyperedge.weight);
         }
         for hash in &nodes_b {
-            self.update_row(*hash, nodes_a.len() as u32);
+            if self.descriptor.directed {
+                self.update_col(*hash, nodes_a.len() as u32, hyperedge.weight);
+            } else {
+                self.update_row(*hash, nodes_a.len() as u32, hyperedge.weight);
+            }
         }
 
-        let value = 1f32 / (total_combinations as f32);
+        let value = hyperedge.weight as f64 / total_combinations as f64;
 
-        let (nodes_a_high, nodes_a_low) = self.get_high_low_nodes(&mut nodes_a);
-        let (nodes_b_high, nodes_b_low) = self.get_high_low_nodes(&mut nodes_b);
-        self.handle_combinations(nodes_a_high, nodes_b_high, value);
-        self.handle_combinations(nodes_a_high, nodes_b_low, value);
-        self.handle_combinations(nodes_a_low, nodes_b_high, value);
-        // Ignore 'low-to-low' combinations
+        if nodes_a.len() > self.hyperedge_trim_n || nodes_b.len() > self.hyperedge_trim_n {
+            self.trimmed_hyperedges.push(TrimmedHyperedge {
+                nodes_a,
+                nodes_b,
+                value,
+            });
+            return;
+        }
+        self.handle_combinations(&nodes_a, &nodes_b, value);
     }
 
-    fn get_high_low_nodes<'a>(
-        &self,
-        nodes: &'a mut SmallVec<[u64; SMALL_VECTOR_SIZE]>,
-    ) -> (&'a [u64], &'a [u64]) {
-        if nodes.len() > self.hyperedge_trim_n {
-            nodes.select_nth_unstable_by_key(self.hyperedge_trim_n, |h| {
-                Reverse(self.hash_2_row.get(h).map_or(0, |r| r.occurrence))
-            });
-            nodes.split_at(self.hyperedge_trim_n)
-        } else {
-            (nodes, &[])
+    /// Creates the edges of hyperedges longer than `hyperedge_trim_n`, between their most frequent
+    /// nodes and the others. Nodes are ranked by their count in the whole input, ties are broken
+    /// by hash, so the kept edges don't depend on which worker handled the hyperedge.
+    fn handle_trimmed_hyperedges(&mut self, node_indexer: &NodeIndexer) {
+        let count = |hash: &u64| {
+            node_indexer
+                .key_2_index
+                .get(hash)
+                .map_or(0, |index| node_indexer.index_2_count[*index])
+        };
+        let trim_n = self.hyperedge_trim_n;
+        for hyperedge in mem::take(&mut self.trimmed_hyperedges) {
+            let TrimmedHyperedge {
+                mut nodes_a,
+                mut nodes_b,
+                value,
+            } = hyperedge;
+            let (nodes_a_high, nodes_a_low) = get_high_low_nodes(&mut nodes_a, trim_n, count);
+            let (nodes_b_high, nodes_b_low) = get_high_low_nodes(&mut nodes_b, trim_n, count);
+            self.handle_combinations(nodes_a_high, nodes_b_high, value);
+            self.handle_combinations(nodes_a_high, nodes_b_low, value);
+            self.handle_combinations(nodes_a_low, nodes_b_high, value);
+            // Ignore 'low-to-low' combinations
         }
     }
 
-    fn handle_combinations(&mut self, a_hashes: &[u64], b_hashes: &[u64], value: f32) {
+    fn handle_combinations(&mut self, a_hashes: &[u64], b_hashes: &[u64], value: f64) {
         for a_hash in a_hashes {
             for b_hash in b_hashes {
-                self.add_pair_symmetric(*a_hash, *b_hash, value);
+                if self.descriptor.skip_self_loops && a_hash == b_hash {
+                    continue;
+                }
+                if self.descriptor.directed {
+                    self.add_pair_directed(*a_hash, *b_hash, value);
+                } else {
+                    self.add_pair_symmetric(*a_hash, *b_hash, value);
+                }
             }
         }
     }
@@ -255,25 +409,50 @@ impl SparseMatrixBuffer {
     /// `a_hash` - hash of a entity for a column A
     /// `b_hash` - hash of a entity for a column B
     /// `count` - total number of combinations in a row
-    fn add_pair_symmetric(&mut self, a_hash: u64, b_hash: u64, value: f32) {
+    fn add_pair_symmetric(&mut self, a_hash: u64, b_hash: u64, value: f64) {
         self.edge_count += 1;
         self.update_edge(a_hash, b_hash, value);
         self.update_edge(b_hash, a_hash, value);
     }
 
-    fn update_row(&mut self, hash: u64, count: u32) {
-        let val = 1f32 / (count as f32);
+    /// Same as `add_pair_symmetric` but only the edge from `a_hash` to `b_hash` is created.
+    fn add_pair_directed(&mut self, a_hash: u64, b_hash: u64, value: f64) {
+        self.edge_count += 1;
+        self.update_edge(a_hash, b_hash, value);
+    }
+
+    fn update_col(&mut self, hash: u64, count: u32, weight: f32) {
+        let val = weight as f64 / count as f64;
+        let e = self.hash_2_row.entry(hash).or_default();
+        e.col_sum += val
+    }
+
+    fn update_row(&mut self, hash: u64, count: u32, weight: f32) {
+        let val = weight as f64 / count as f64;
         let e = self.hash_2_row.entry(hash).or_default();
-        e.occurrence += count;
         e.row_sum += val
     }
 
-    fn update_edge(&mut self, a_hash: u64, b_hash: u64, val: f32) {
+    fn update_edge(&mut self, a_hash: u64, b_hash: u64, val: f64) {
         let e = self.hashes_2_edge.entry((a_hash, b_hash)).or_default();
         *e += val;
     }
 }
 
+/// Splits nodes into `trim_n` high nodes, the most frequent ones, and the low rest.
+fn get_high_low_nodes(
+    nodes: &mut [u64],
+    trim_n: usize,
+    count: impl Fn(&u64) -> u32,
+) -> (&[u64], &[u64]) {
+    if nodes.len() > trim_n {
+        nodes.select_nth_unstable_by_key(trim_n, |hash| (Reverse(count(hash)), *hash));
+        nodes.split_at(trim_n)
+    } else {
+        (nodes, &[])
+    }
+}
+
 #[derive(Debug)]
 pub struct SparseMatrixBuffersReducer {
     descriptor: SparseMatrixDescriptor,
@@ -320,30 +499,61 @@ impl SparseMatrixBuffersReducer {
             .unwrap()
             .install(|| {
                 let node_indexer = self.node_indexer;
+                let mut buffers = self.buffers;
+                buffers
+                    .par_iter_mut()
+                    .for_each(|buffer| buffer.handle_trimmed_hyperedges(&node_indexer));
 
                 // Extract buffers so their fields can be moved to reducing functions
-                let (hash_2_row_maps, hashes_2_edge_map): (Vec<_>, Vec<_>) = self
-                    .buffers
+                let (hash_2_row_maps, hashes_2_edge_map): (Vec<_>, Vec<_>) = buffers
                     .into_iter()
                     .map(|b| (b.hash_2_row, b.hashes_2_edge))
                     .unzip();
-                let entities =
-                    SparseMatrixBuffersReducer::reduce_to_entities(&node_indexer, hash_2_row_maps);
+                let mut entities =
+                    SparseMatrixBuffersReducer::reduce_to_entities(&node_indexer, &hash_2_row_maps);
+                // Symmetric normalization of directed matrices needs weights of incoming edges
+                let mut col_sums: Vec<f32> = if self.descriptor.directed {
+                    SparseMatrixBuffersReducer::reduce_to_col_sums(&node_indexer, &hash_2_row_maps)
+                } else {
+                    entities.iter().map(|e| e.row_sum).collect()
+                };
+                let dropped_entities = hash_2_row_maps.par_iter().any(|entity_map| {
+                    entity_map
+                        .keys()
+                        .any(|hash| !node_indexer.key_2_index.contains_key(hash))
+                });
+                drop(hash_2_row_maps);
                 let mut edges: Vec<_> =
                     SparseMatrixBuffersReducer::reduce_to_edges(&node_indexer, hashes_2_edge_map);
                 edges.par_sort_by_key(|entry| (entry.row, entry.col));
 
-                let slices: Vec<_> = edges
-                    .iter()
-                    .enumerate()
-                    .group_by(|(_, entry)| entry.row)
-                    .into_iter()
-                    .map(|(_, mut group)| {
-                        let first = group.next().expect("Group have at least one element");
-                        let last = group.last().unwrap_or(first);
-                        (first.0, last.0 + 1)
-                    })
-                    .collect();
+                if dropped_entities {
+                    // Edges to dropped entities are gone, sums are taken from the kept ones
+                    let mut row_sums = vec![0.0f64; entities.len()];
+                    let mut in_sums = vec![0.0f64; entities.len()];
+                    for entry in &edges {
+                        row_sums[entry.row as usize] += entry.value as f64;
+                        in_sums[entry.col as usize] += entry.value as f64;
+                    }
+                    for (entity, row_sum) in entities.iter_mut().zip(&row_sums) {
+                        entity.row_sum = *row_sum as f32;
+                    }
+                    let col_sums_source = if self.descriptor.directed {
+                        &in_sums
+                    } else {
+                        &row_sums
+                    };
+                    col_sums = col_sums_source.iter().map(|sum| *sum as f32).collect();
+                }
+
+                // Every entity gets a slice, nodes without outgoing edges (sinks of directed
+                // matrices) get an empty one
+                let mut slices: Vec<_> = vec![(0, 0); entities.len()];
+                for (row, mut group) in &edges.iter().enumerate().group_by(|(_, entry)| entry.row) {
+                    let first = group.next().expect("Group have at least one element");
+                    let last = group.last().unwrap_or(first);
+                    slices[row as usize] = (first.0, last.0 + 1);
+                }
 
                 let mut edges: Vec<_> = edges
                     .into_par_iter()
@@ -366,7 +576,7 @@ impl SparseMatrixBuffersReducer {
 
                             let left_markov_normalization = row_sum;
                             let symmetric_markov_normalization = {
-                                let col_sum = entities[edge.other_entity_ix as usize].row_sum;
+                                let col_sum = col_sums[edge.other_entity_ix as usize];
                                 (row_sum * col_sum).sqrt()
                             };
                             edge.left_markov_value = value / left_markov_normalization;
@@ -387,33 +597,61 @@ impl SparseMatrixBuffersReducer {
 
     fn reduce_to_entities(
         node_indexer: &NodeIndexer,
-        entity_maps: Vec<HashMap<u64, Row, BuildHasherDefault<FxHasher>>>,
+        entity_maps: &[HashMap<u64, Row, BuildHasherDefault<FxHasher>>],
     ) -> Vec<Entity> {
         node_indexer
             .index_2_key
             .par_iter()
-            .map(|hash| {
-                let mut entity_agg = Entity { row_sum: 0.0 };
+            .zip(node_indexer.index_2_count.par_iter())
+            .map(|(hash, occurrence)| {
+                let mut row_sum = 0.0;
                 for entity_map in entity_maps.iter() {
                     if let Some(entity) = entity_map.get(hash) {
-                        entity_agg.row_sum += entity.row_sum;
+                        row_sum += entity.row_sum;
                     }
                 }
-                entity_agg
+                Entity {
+                    row_sum: row_sum as f32,
+                    occurrence: *occurrence,
+                }
+            })
+            .collect()
+    }
+
+    fn reduce_to_col_sums(
+        node_indexer: &NodeIndexer,
+        entity_maps: &[HashMap<u64, Row, BuildHasherDefault<FxHasher>>],
+    ) -> Vec<f32> {
+        node_indexer
+            .index_2_key
+            .par_iter()
+            .map(|hash| {
+                entity_maps
+                    .iter()
+                    .filter_map(|entity_map| entity_map.get(hash))
+                    .map(|entity| entity.col_sum)
+                    .sum::<f64>() as f32
             })
             .collect()
     }
 
     fn reduce_to_edges(
         node_indexer: &NodeIndexer,
-        edge_maps: Vec<HashMap<(u64, u64), f32, BuildHasherDefault<FxHasher>>>,
+        edge_maps: Vec<HashMap<(u64, u64), f64, BuildHasherDefault<FxHasher>>>,
     ) -> Vec<EdgeEntry> {
         // Dashmap to have concurrent write access with par_drain
         // par_drain is recommended to not increase peak memory usage
-        let reduced_edge_map: DashMap<(u64, u64), f32, BuildHasherDefault<FxHasher>> =
+        let reduced_edge_map: DashMap<(u64, u64), f64, BuildHasherDefault<FxHasher>> =
             Default::default();
         for mut edge_map in edge_maps.into_iter() {
             edge_map.par_drain().for_each(|(k, v)| {
+                // Entities dropped from the indexer (e.g. too rare) lose their edges
+                let (row_hash, col_hash) = k;
+                if !node_indexer.key_2_index.contains_key(&row_hash)
+                    || !node_indexer.key_2_index.contains_key(&col_hash)
+                {
+                    return;
+                }
                 reduced_edge_map
                     .entry(k)
                     .and_modify(|rv| *rv += v)
@@ -431,8 +669,49 @@ impl SparseMatrixBuffersReducer {
                     .key_2_index
                     .get(&col_hash)
                     .expect("Hash value was indexed") as u32;
-                EdgeEntry { row, col, value }
+                EdgeEntry {
+                    row,
+                    col,
+                    value: value as f32,
+                }
             })
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::sparse_matrix_builder::{NodeIndexerBuilder, SyncNodeIndexerBuilder};
+
+    #[test]
+    fn report_hash_collisions() {
+        let builder = SyncNodeIndexerBuilder::default();
+        builder.process(1, "a", 0);
+        builder.process(1, "a", 0);
+        builder.process(1, "b", 0);
+        builder.process(1, "b", 1);
+        builder.process(2, "c", 0);
+        let node_indexer = builder.finish();
+
+        assert_eq!(node_indexer.index_2_entity_id, vec!["a", "c"]);
+        assert_eq!(node_indexer.index_2_count, vec![4, 1]);
+        let collision = &node_indexer.hash_collisions["b"];
+        assert_eq!(collision.entity_id, "a");
+        assert_eq!(collision.count, 2);
+        assert_eq!(node_indexer.hash_collisions.len(), 1);
+    }
+
+    #[test]
+    fn report_column_merges() {
+        let builder = SyncNodeIndexerBuilder::default();
+        builder.process(1, "a", 0);
+        builder.process(2, "b", 0);
+        builder.process(1, "a", 1);
+        builder.process(2, "b", 0);
+        let node_indexer = builder.finish();
+
+        assert_eq!(node_indexer.column_merges.len(), 1);
+        assert!(node_indexer.column_merges.contains(&1));
+        assert_eq!(node_indexer.index_2_column_id, vec![0, 0]);
+    }
+}